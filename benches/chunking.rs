@@ -0,0 +1,135 @@
+//! Compares the token-budget chunker against the previous algorithm, which
+//! re-tokenized the whole remainder and then binary-searched growing prefixes
+//! for every chunk.
+//!
+//! ```bash
+//! cargo bench --bench chunking                          # mock char tokenizer
+//! LFM_MODEL=model.gguf cargo bench --bench chunking     # real llama.cpp tokenizer
+//! ```
+//!
+//! `LFM_BENCH_BYTES` limits how much of the sample text is used (default 200000).
+
+use crossbeam_channel::unbounded;
+use lfm_cmd::chunker::{parse_and_chunk, ChunkOptions};
+use lfm_cmd::{InferenceBackend, InferenceSession, LlamaInference, MockBackend, Result};
+use llama_cpp_2::model::params::LlamaModelParams;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+const SAMPLE: &str = "sample-input/吾輩は猫である.txt";
+const TARGET_TOKENS: usize = 512;
+
+/// Wraps a backend and counts how much work the tokenizer is asked to do.
+struct CountingBackend {
+    inner: Box<dyn InferenceBackend>,
+    calls: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl CountingBackend {
+    fn reset(&self) {
+        self.calls.store(0, Ordering::Relaxed);
+        self.bytes.store(0, Ordering::Relaxed);
+    }
+}
+
+impl InferenceBackend for CountingBackend {
+    fn tokenize(&self, text: &str) -> Result<Vec<i32>> {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(text.len(), Ordering::Relaxed);
+        self.inner.tokenize(text)
+    }
+
+    fn new_session(&self, n_ctx: u32) -> Result<Box<dyn InferenceSession + '_>> {
+        self.inner.new_session(n_ctx)
+    }
+}
+
+/// The pre-streaming chunker, kept here only as a baseline.
+fn legacy_chunks(backend: &dyn InferenceBackend, text: &str, target_tokens: usize) -> Result<usize> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut start_idx = 0;
+    let mut chunks = 0;
+    while start_idx < chars.len() {
+        let mut left = start_idx + 1;
+        let mut right = chars.len();
+        let mut best_idx = right;
+        let remainder: String = chars[start_idx..right].iter().collect();
+        if backend.count_tokens(&remainder)? > target_tokens {
+            while left <= right {
+                let mid = left + (right - left) / 2;
+                let chunk_str: String = chars[start_idx..mid].iter().collect();
+                if backend.count_tokens(&chunk_str)? <= target_tokens {
+                    best_idx = mid;
+                    left = mid + 1;
+                } else {
+                    right = mid - 1;
+                }
+            }
+            if let Some(i) = (start_idx..best_idx).rev().find(|&i| matches!(chars[i], '\n' | '。' | '.')) {
+                best_idx = i + 1;
+            }
+        }
+        chunks += 1;
+        start_idx = best_idx;
+    }
+    Ok(chunks)
+}
+
+fn streaming_chunks(backend: &dyn InferenceBackend, text: &str, target_tokens: usize) -> Result<usize> {
+    let (tx, rx) = unbounded();
+    parse_and_chunk(
+        backend,
+        text.as_bytes(),
+        None,
+        0,
+        tx,
+        &ChunkOptions {
+            target_tokens,
+            ..ChunkOptions::default()
+        },
+    )?;
+    Ok(rx.iter().count())
+}
+
+fn report(name: &str, backend: &CountingBackend, chunks: usize, elapsed: Duration) {
+    println!(
+        "{:<10} {:>6} chunks {:>10.1} ms {:>9} tokenizer calls {:>14} bytes tokenized",
+        name,
+        chunks,
+        elapsed.as_secs_f64() * 1000.0,
+        backend.calls.load(Ordering::Relaxed),
+        backend.bytes.load(Ordering::Relaxed),
+    );
+}
+
+fn main() -> Result<()> {
+    let limit: usize = std::env::var("LFM_BENCH_BYTES").ok().and_then(|v| v.parse().ok()).unwrap_or(200_000);
+    let full = std::fs::read_to_string(SAMPLE)?;
+    let mut end = limit.min(full.len());
+    while !full.is_char_boundary(end) {
+        end -= 1;
+    }
+    let text = &full[..end];
+
+    let inner: Box<dyn InferenceBackend> = match std::env::var("LFM_MODEL") {
+        Ok(path) => Box::new(LlamaInference::load(Path::new(&path), &LlamaModelParams::default(), 4096)?),
+        Err(_) => Box::new(MockBackend::echo()),
+    };
+    let backend = CountingBackend { inner, calls: AtomicUsize::new(0), bytes: AtomicUsize::new(0) };
+
+    println!("{} ({} bytes), target {} tokens", SAMPLE, text.len(), TARGET_TOKENS);
+
+    backend.reset();
+    let started = Instant::now();
+    let chunks = legacy_chunks(&backend, text, TARGET_TOKENS)?;
+    report("legacy", &backend, chunks, started.elapsed());
+
+    backend.reset();
+    let started = Instant::now();
+    let chunks = streaming_chunks(&backend, text, TARGET_TOKENS)?;
+    report("linear", &backend, chunks, started.elapsed());
+
+    Ok(())
+}
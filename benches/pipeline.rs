@@ -0,0 +1,97 @@
+//! Measures the wall clock of the reduce strategies and how much of the
+//! reduce overlaps the map: every generation call sleeps for a simulated
+//! latency, so the timings show when intermediate reduces start relative to
+//! the last chunk result.
+//!
+//! ```bash
+//! cargo bench --bench pipeline
+//! LFM_BENCH_STRATEGY=tree cargo bench --bench pipeline
+//! ```
+//!
+//! `LFM_BENCH_BYTES` limits how much of the sample text is used (default
+//! 300000), `LFM_BENCH_MAP_MS` sets the simulated latency of a map call
+//! (default 20) and `LFM_BENCH_REDUCE_MS` that of a reduce call per 10,000
+//! characters of prompt (default 200), since prefill grows with the input.
+
+use clap::ValueEnum;
+use lfm_cmd::config::ReduceStrategy;
+use lfm_cmd::{AppConfig, MockBackend, Pipeline, PipelineEvent, Result};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SAMPLE: &str = "sample-input/吾輩は猫である.txt";
+const TARGET_TOKENS: usize = 512;
+const WORKERS: usize = 2;
+
+fn env_or(name: &str, default: u64) -> u64 {
+    std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+fn main() -> Result<()> {
+    let limit = env_or("LFM_BENCH_BYTES", 300_000) as usize;
+    let map_latency = Duration::from_millis(env_or("LFM_BENCH_MAP_MS", 20));
+    let reduce_latency = Duration::from_millis(env_or("LFM_BENCH_REDUCE_MS", 200));
+    let strategy = std::env::var("LFM_BENCH_STRATEGY")
+        .ok()
+        .and_then(|v| ReduceStrategy::from_str(&v, true).ok())
+        .unwrap_or_default();
+
+    let full = std::fs::read_to_string(SAMPLE)?;
+    let mut end = limit.min(full.len());
+    while !full.is_char_boundary(end) {
+        end -= 1;
+    }
+    let text = &full[..end];
+
+    // Chunk results long enough to trigger several intermediate reduces
+    let backend = Arc::new(MockBackend::with_responder(move |prompt| {
+        if prompt.contains("[Data ") || prompt.contains("[Intermediate Summary ") {
+            thread::sleep(reduce_latency * prompt.chars().count() as u32 / 10_000);
+            "要約。".repeat(200)
+        } else {
+            thread::sleep(map_latency);
+            "結果。".repeat(400)
+        }
+    }));
+    let config = AppConfig { reduce_strategy: strategy, ..AppConfig::default() };
+    let pipeline = Pipeline::builder(backend).config(config).chunk_tokens(TARGET_TOKENS).workers(WORKERS).build();
+
+    println!(
+        "{} ({} bytes), {:?} reduce, {} workers, {:?} per map call, {:?} per 10k reduce chars",
+        SAMPLE,
+        text.len(),
+        strategy,
+        WORKERS,
+        map_latency,
+        reduce_latency
+    );
+
+    let started = Instant::now();
+    let mut first_reduce = None;
+    let mut last_chunk = Duration::ZERO;
+    let mut chunks = 0;
+    let mut reduces = 0;
+    pipeline.stream(text.as_bytes(), |event| match event {
+        PipelineEvent::Chunk { .. } | PipelineEvent::SilentChunk { .. } => {
+            chunks += 1;
+            last_chunk = started.elapsed();
+        }
+        PipelineEvent::IntermediateReduceStarted { .. } => {
+            reduces += 1;
+            first_reduce.get_or_insert(started.elapsed());
+        }
+        _ => {}
+    })?;
+    let total = started.elapsed();
+
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    println!("{:>6} chunks, last result at {:>9.1} ms", chunks, ms(last_chunk));
+    match first_reduce {
+        Some(first) => println!("{:>6} intermediate reduces, first at {:>9.1} ms", reduces, ms(first)),
+        None => println!("{:>6} intermediate reduces", reduces),
+    }
+    println!("{:>6} total {:>25.1} ms", "", ms(total));
+
+    Ok(())
+}
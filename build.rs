@@ -3,29 +3,37 @@ use std::path::PathBuf;
 use std::process::Command;
 
 fn main() {
-    println!("cargo:rustc-link-lib=framework=Foundation");
-    println!("cargo:rustc-link-lib=framework=Metal");
-    println!("cargo:rustc-link-lib=framework=MetalPerformanceShaders");
-    println!("cargo:rustc-link-lib=framework=Accelerate");
-    println!("cargo:rustc-env=LLAMA_METAL=on");
+    let target_os = env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
 
-    let out_dir = env::var("OUT_DIR").unwrap();
-    let dest_path = PathBuf::from(out_dir).join("LFM2.5-1.2B-Instruct-Q4_K_M.gguf");
+    // llama.cpp is built with Metal on macOS (see Cargo.toml), which needs the Apple frameworks
+    if target_os == "macos" {
+        println!("cargo:rustc-link-lib=framework=Foundation");
+        println!("cargo:rustc-link-lib=framework=Metal");
+        println!("cargo:rustc-link-lib=framework=MetalPerformanceShaders");
+        println!("cargo:rustc-link-lib=framework=Accelerate");
+        println!("cargo:rustc-env=LLAMA_METAL=on");
+    }
+
+    // The model is only downloaded when it is going to be embedded with `include_bytes!`
+    if env::var_os("CARGO_FEATURE_EMBEDDED_MODEL").is_some() {
+        let out_dir = env::var("OUT_DIR").unwrap();
+        let dest_path = PathBuf::from(out_dir).join("LFM2.5-1.2B-Instruct-Q4_K_M.gguf");
 
-    if !dest_path.exists() {
-        println!("cargo:warning=Downloading LFM2.5 1.2B model (730MB) to OUT_DIR for embedding...");
-        let status = Command::new("curl")
-            .args(&[
-                "-L",
-                "-o",
-                dest_path.to_str().unwrap(),
-                "https://huggingface.co/LiquidAI/LFM2.5-1.2B-Instruct-GGUF/resolve/main/LFM2.5-1.2B-Instruct-Q4_K_M.gguf"
-            ])
-            .status()
-            .expect("Failed to execute curl");
+        if !dest_path.exists() {
+            println!("cargo:warning=Downloading LFM2.5 1.2B model (730MB) to OUT_DIR for embedding...");
+            let status = Command::new("curl")
+                .args([
+                    "-L",
+                    "-o",
+                    dest_path.to_str().unwrap(),
+                    "https://huggingface.co/LiquidAI/LFM2.5-1.2B-Instruct-GGUF/resolve/main/LFM2.5-1.2B-Instruct-Q4_K_M.gguf"
+                ])
+                .status()
+                .expect("Failed to execute curl");
 
-        if !status.success() {
-            panic!("Failed to download model file.");
+            if !status.success() {
+                panic!("Failed to download model file.");
+            }
         }
     }
 
@@ -0,0 +1,64 @@
+use llama_cpp_2::llama_backend::LlamaBackend;
+use llama_cpp_2::model::params::LlamaModelParams;
+use llama_cpp_2::model::LlamaModel;
+use std::path::Path;
+use crate::error::{Error, Result};
+use crate::generator::{GenerationParams, Generator};
+
+/// Everything the pipeline needs from an inference engine.
+///
+/// Shared across worker and reducer threads; each thread opens its own
+/// `InferenceSession` so no locking is needed during generation.
+pub trait InferenceBackend: Send + Sync {
+    /// Tokenizes `text` without adding a BOS token.
+    fn tokenize(&self, text: &str) -> Result<Vec<i32>>;
+
+    fn count_tokens(&self, text: &str) -> Result<usize> {
+        Ok(self.tokenize(text)?.len())
+    }
+
+    /// Opens a new generation session with a context window of `n_ctx` tokens.
+    fn new_session(&self, n_ctx: u32) -> Result<Box<dyn InferenceSession + '_>>;
+}
+
+/// A single generation context (the KV cache of one llama.cpp context).
+pub trait InferenceSession {
+    /// Generates a completion for `prompt`, passing every decoded piece to `sink`
+    /// as it is produced. Returns the full generated text.
+    fn generate(&mut self, prompt: &str, params: &GenerationParams, sink: &mut dyn FnMut(&str)) -> Result<String>;
+}
+
+/// `InferenceBackend` backed by a GGUF model loaded through llama.cpp.
+pub struct LlamaInference {
+    backend: LlamaBackend,
+    model: LlamaModel,
+    batch_size: usize,
+}
+
+impl LlamaInference {
+    pub fn new(backend: LlamaBackend, model: LlamaModel, batch_size: usize) -> Self {
+        Self { backend, model, batch_size }
+    }
+
+    /// Initializes the llama backend and loads the GGUF model at `path`.
+    pub fn load(path: &Path, params: &LlamaModelParams, batch_size: usize) -> Result<Self> {
+        let backend = LlamaBackend::init()
+            .map_err(|e| Error::ModelLoad(format!("failed to initialize llama backend: {}", e)))?;
+        let model = LlamaModel::load_from_file(&backend, path, params)
+            .map_err(|e| Error::ModelLoad(format!("{}: {}", path.display(), e)))?;
+        Ok(Self::new(backend, model, batch_size))
+    }
+}
+
+impl InferenceBackend for LlamaInference {
+    fn tokenize(&self, text: &str) -> Result<Vec<i32>> {
+        let tokens = self.model
+            .str_to_token(text, llama_cpp_2::model::AddBos::Never)
+            .map_err(|e| Error::Inference(format!("failed to tokenize: {}", e)))?;
+        Ok(tokens.into_iter().map(|token| token.0).collect())
+    }
+
+    fn new_session(&self, n_ctx: u32) -> Result<Box<dyn InferenceSession + '_>> {
+        Ok(Box::new(Generator::new(&self.model, &self.backend, n_ctx, self.batch_size)?))
+    }
+}
@@ -1,74 +1,782 @@
+use crate::backend::InferenceBackend;
+use crate::config::DEFAULT_CHUNK_TOKENS;
+use crate::encoding::{DecodeReport, InputDecoder};
+use crate::error::Result;
+use crate::preprocess::Preprocess;
 use crate::types::ChunkTask;
+use clap::ValueEnum;
 use crossbeam_channel::Sender;
-use llama_cpp_2::model::LlamaModel;
-use std::io::{self, Read};
+use encoding_rs::Encoding;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+use std::sync::Arc;
 
+/// Bytes pulled from the reader per `read` call. Memory use stays bounded by
+/// this plus one chunk of pending text, no matter how large the input is.
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Text without any unit boundary is force-split once it grows past this, so a
+/// single endless line cannot hold back the stream (JSON records excepted).
+const MAX_PARTIAL_BYTES: usize = 64 * 1024;
+
+/// How `parse_and_chunk` turns raw input into chunks.
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkOptions {
+    /// Max tokens per chunk (excluding overlap).
+    pub target_tokens: usize,
+    pub overlap_tokens: usize,
+    pub mode: ChunkMode,
+    /// Input encoding; `None` detects it (see `InputDecoder`).
+    pub encoding: Option<&'static Encoding>,
+    pub preprocess: Preprocess,
+}
+
+impl Default for ChunkOptions {
+    fn default() -> Self {
+        Self {
+            target_tokens: DEFAULT_CHUNK_TOKENS,
+            overlap_tokens: 0,
+            mode: ChunkMode::default(),
+            encoding: None,
+            preprocess: Preprocess::default(),
+        }
+    }
+}
+
+/// What `parse_and_chunk` read from one input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkReport {
+    pub decode: DecodeReport,
+    /// Chunks produced (and sent, unless the workers stopped early).
+    pub chunks: usize,
+}
+
+/// Decodes and preprocesses `reader`, then sends every chunk to `worker_tx`
+/// as soon as its split point is known. Chunks are tagged with `source` and
+/// numbered from `first_index`.
 pub fn parse_and_chunk(
-    model: &LlamaModel,
+    backend: &dyn InferenceBackend,
+    mut reader: impl Read,
+    source: Option<Arc<Path>>,
+    first_index: usize,
     worker_tx: Sender<ChunkTask>,
+    options: &ChunkOptions,
+) -> Result<ChunkReport> {
+    let mut buf = vec![0u8; READ_BUFFER_SIZE];
+    // Keeps partial sequences at buffer edges until the next read completes them
+    let mut decoder = InputDecoder::new(options.encoding);
+    let mut decoded = String::new();
+    let mut preprocessor = options.preprocess.preprocessor();
+    let mut preprocessed = String::new();
+    let mut chunker = Chunker::new(backend, options.mode, options.target_tokens, options.overlap_tokens)
+        .with_source(source, first_index);
+
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let eof = n == 0;
+
+        decoded.clear();
+        decoder.decode(&buf[..n], &mut decoded, eof);
+        let text = match preprocessor.as_mut() {
+            Some(preprocessor) => {
+                preprocessed.clear();
+                preprocessor.push(&decoded, &mut preprocessed);
+                if eof {
+                    preprocessor.finish(&mut preprocessed);
+                }
+                &preprocessed
+            }
+            None => &decoded,
+        };
+
+        // Emit every chunk whose split point is already known
+        let mut tasks = chunker.push(text)?;
+        if eof {
+            tasks.extend(chunker.finish()?);
+        }
+        for task in tasks {
+            if worker_tx.send(task).is_err() {
+                // Every worker has exited; the worker error is reported by the pipeline
+                return Ok(chunker.report(&decoder));
+            }
+        }
+
+        if eof {
+            return Ok(chunker.report(&decoder));
+        }
+    }
+}
+
+/// How the input is cut into units before token-budget packing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkMode {
+    /// Sentences (`\n`, `。`, `.`, `！`, `？`, closing `」`), preferring paragraph breaks.
+    #[default]
+    Prose,
+    /// Lines, preferring headings; never splits inside fenced code blocks.
+    Markdown,
+    /// Lines, preferring top-level item boundaries by indentation and braces.
+    Code,
+    /// One record per line (JSON Lines, logs); a record is never split.
+    #[value(alias = "log")]
+    Jsonl,
+}
+
+/// Sentence terminators for `ChunkMode::Prose`. A closing `」` or `』` ends a
+/// sentence by itself too, as in dialogue without `。` before it (「…」と言った).
+const SENTENCE_ENDS: [char; 9] = ['\n', '。', '.', '！', '？', '!', '?', '」', '』'];
+
+/// Closing brackets and quotes that stay attached to the sentence they end.
+const SENTENCE_CLOSERS: [char; 6] = ['」', '』', '）', ')', '"', '\''];
+
+/// A sentence (or line) together with its token count, tokenized exactly once.
+struct Segment {
+    text: String,
+    tokens: usize,
+    /// How good a place the start of this segment is to begin a new chunk.
+    /// Higher wins (paragraphs over sentences, headings over lines).
+    break_before: u8,
+}
+
+/// Incremental token-budget chunker.
+///
+/// Text is cut into units (sentences or lines, depending on `ChunkMode`); each
+/// unit is tokenized once and the counts are accumulated to pick split points,
+/// so the tokenizer sees every byte of the input a single time. Only a unit
+/// that is larger than the budget by itself falls back to a binary search
+/// within it, and atomic units (fenced code, JSON records) are never split.
+///
+/// With `overlap_tokens > 0`, every chunk after the first is prefixed with the
+/// trailing sentences of the previous chunk (on top of the `target_tokens` budget).
+pub struct Chunker<'a> {
+    backend: &'a dyn InferenceBackend,
+    mode: ChunkMode,
     target_tokens: usize,
-) {
-    let stdin = io::stdin();
-    let mut reader = stdin.lock();
-    
-    let mut full_text = String::new();
-    reader.read_to_string(&mut full_text).expect("Failed to read stdin");
-    let mut chunk_index = 0;
-    let mut start_idx = 0;
-
-    let chars: Vec<char> = full_text.chars().collect();
-    
-    while start_idx < chars.len() {
-        // Binary search to find approximately specified tokens
-        let mut left = start_idx + 1;
-        let mut right = chars.len();
-        let mut best_idx = right;
-        
-        // If remaining is small, directly take the rest
-        let remainder: String = chars[start_idx..right].iter().collect();
-        let remainder_tokens = model.str_to_token(&remainder, llama_cpp_2::model::AddBos::Never).unwrap_or_default().len();
-        
-        if remainder_tokens <= target_tokens {
-            best_idx = right;
+    overlap_tokens: usize,
+    /// Text after the last unit boundary.
+    partial: String,
+    /// Offset in `partial` from which boundaries still need to be searched.
+    scan_from: usize,
+    segments: VecDeque<Segment>,
+    pending_tokens: usize,
+    /// Body segments of the last emitted chunk, the source of the next overlap.
+    previous: Vec<Segment>,
+    /// Byte offset of the first queued segment in the text pushed so far.
+    offset: usize,
+    source: Option<Arc<Path>>,
+    first_index: usize,
+    next_index: usize,
+    lines: LineState,
+}
+
+/// Structure tracked across lines for the line-based modes.
+#[derive(Default)]
+struct LineState {
+    /// Consecutive newlines at the end of the text segmented so far.
+    trailing_newlines: usize,
+    /// Opening fence (e.g. "```") while inside a Markdown code block.
+    fence: Option<String>,
+    fence_text: String,
+    fence_break: u8,
+    /// Brace depth for `ChunkMode::Code`.
+    depth: usize,
+    closed_block: bool,
+}
+
+impl<'a> Chunker<'a> {
+    pub fn new(backend: &'a dyn InferenceBackend, mode: ChunkMode, target_tokens: usize, overlap_tokens: usize) -> Self {
+        Self {
+            backend,
+            mode,
+            target_tokens: target_tokens.max(1),
+            overlap_tokens,
+            partial: String::new(),
+            scan_from: 0,
+            segments: VecDeque::new(),
+            pending_tokens: 0,
+            previous: Vec::new(),
+            offset: 0,
+            source: None,
+            first_index: 0,
+            next_index: 0,
+            lines: LineState::default(),
+        }
+    }
+
+    /// Tags every chunk with `source` and numbers them from `first_index`.
+    pub fn with_source(mut self, source: Option<Arc<Path>>, first_index: usize) -> Self {
+        self.source = source;
+        self.first_index = first_index;
+        self.next_index = first_index;
+        self
+    }
+
+    fn report(&self, decoder: &InputDecoder) -> ChunkReport {
+        ChunkReport {
+            decode: decoder.report(),
+            chunks: self.next_index - self.first_index,
+        }
+    }
+
+    /// Feeds decoded text and returns every chunk that is now complete.
+    pub fn push(&mut self, text: &str) -> Result<Vec<ChunkTask>> {
+        self.partial.push_str(text);
+        match self.mode {
+            ChunkMode::Prose => self.split_sentences(false)?,
+            _ => self.split_lines(false)?,
+        }
+        self.take_ready(false)
+    }
+
+    /// Flushes the remaining text at end of input.
+    pub fn finish(&mut self) -> Result<Vec<ChunkTask>> {
+        match self.mode {
+            ChunkMode::Prose => self.split_sentences(true)?,
+            _ => self.split_lines(true)?,
+        }
+        if self.lines.fence.take().is_some() {
+            // Unterminated code block: keep it whole
+            let text = std::mem::take(&mut self.lines.fence_text);
+            self.add_segment(text, self.lines.fence_break, true)?;
+        }
+        self.take_ready(true)
+    }
+
+    fn split_sentences(&mut self, eof: bool) -> Result<()> {
+        let mut start = 0;
+        while let Some(pos) = self.partial[self.scan_from..].find(SENTENCE_ENDS) {
+            let terminator = self.scan_from + pos;
+            let mut end = terminator + self.partial[terminator..].chars().next().unwrap().len_utf8();
+            end += self.partial[end..]
+                .chars()
+                .take_while(|c| SENTENCE_CLOSERS.contains(c))
+                .map(char::len_utf8)
+                .sum::<usize>();
+            if end == self.partial.len() && !eof {
+                // A closing quote may still follow in the next read
+                self.scan_from = terminator;
+                break;
+            }
+            let sentence = self.partial[start..end].to_string();
+            let break_before = match self.lines.trailing_newlines {
+                0 => 1,
+                1 => 2,
+                _ => 3,
+            };
+            self.track_newlines(&sentence);
+            self.add_segment(sentence, break_before, false)?;
+            start = end;
+            self.scan_from = end;
+        }
+        self.partial.drain(..start);
+        self.scan_from = self.scan_from.saturating_sub(start).min(self.partial.len());
+
+        if eof || self.partial.len() > MAX_PARTIAL_BYTES {
+            if !self.partial.is_empty() {
+                let text = std::mem::take(&mut self.partial);
+                self.add_segment(text, 0, false)?;
+            }
+            self.scan_from = 0;
+        }
+        Ok(())
+    }
+
+    fn track_newlines(&mut self, text: &str) {
+        let trailing = text.len() - text.trim_end_matches('\n').len();
+        if trailing == text.len() {
+            self.lines.trailing_newlines += trailing;
         } else {
-            // Binary search max matching offset
-            while left <= right {
-                let mid = left + (right - left) / 2;
-                let chunk_str: String = chars[start_idx..mid].iter().collect();
-                let tokens = model.str_to_token(&chunk_str, llama_cpp_2::model::AddBos::Never).unwrap_or_default().len();
-                
-                if tokens <= target_tokens {
-                    best_idx = mid;
-                    left = mid + 1;
-                } else {
-                    right = mid - 1;
-                }
+            self.lines.trailing_newlines = trailing;
+        }
+    }
+
+    fn split_lines(&mut self, eof: bool) -> Result<()> {
+        let mut start = 0;
+        while let Some(pos) = self.partial[self.scan_from..].find('\n') {
+            let end = self.scan_from + pos + 1;
+            let line = self.partial[start..end].to_string();
+            self.add_line(line)?;
+            start = end;
+            self.scan_from = end;
+        }
+        self.partial.drain(..start);
+        self.scan_from = self.partial.len();
+
+        // Records are atomic in JSONL mode, so only force-flush other modes
+        let oversized = self.partial.len() > MAX_PARTIAL_BYTES && self.mode != ChunkMode::Jsonl;
+        if (eof || oversized) && !self.partial.is_empty() {
+            let line = std::mem::take(&mut self.partial);
+            self.add_line(line)?;
+            self.scan_from = 0;
+        }
+        Ok(())
+    }
+
+    fn add_line(&mut self, line: String) -> Result<()> {
+        match self.mode {
+            ChunkMode::Prose => unreachable!("prose is split into sentences"),
+            ChunkMode::Markdown => self.add_markdown_line(line),
+            ChunkMode::Code => self.add_code_line(line),
+            ChunkMode::Jsonl => self.add_segment(line, 1, true),
+        }
+    }
+
+    fn add_markdown_line(&mut self, line: String) -> Result<()> {
+        let trimmed = line.trim_start();
+
+        if let Some(fence) = &self.lines.fence {
+            let closes = trimmed.starts_with(fence.as_str())
+                && trimmed.trim_end().chars().all(|c| c == fence.chars().next().unwrap());
+            self.lines.fence_text.push_str(&line);
+            if closes {
+                self.lines.fence = None;
+                self.lines.trailing_newlines = 0;
+                let text = std::mem::take(&mut self.lines.fence_text);
+                return self.add_segment(text, self.lines.fence_break, true);
+            }
+            return Ok(());
+        }
+
+        let blank = trimmed.trim_end().is_empty();
+        let heading_level = trimmed.chars().take_while(|&c| c == '#').count();
+        let is_heading = (1..=6).contains(&heading_level)
+            && trimmed[heading_level..].starts_with([' ', '\t', '\n']);
+        let break_before = if is_heading {
+            if heading_level <= 2 { 4 } else { 3 }
+        } else if self.lines.trailing_newlines >= 2 || (self.lines.trailing_newlines == 1 && blank) {
+            2
+        } else {
+            1
+        };
+
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            let marker = trimmed.chars().next().unwrap();
+            let fence: String = trimmed.chars().take_while(|&c| c == marker).collect();
+            self.lines.fence = Some(fence);
+            self.lines.fence_text = line;
+            self.lines.fence_break = break_before;
+            return Ok(());
+        }
+
+        self.lines.trailing_newlines = if blank { self.lines.trailing_newlines + 1 } else { 1 };
+        self.add_segment(line, break_before, false)
+    }
+
+    fn add_code_line(&mut self, line: String) -> Result<()> {
+        let trimmed = line.trim();
+        let blank = trimmed.is_empty();
+        let top_level = self.lines.depth == 0
+            && !blank
+            && !line.starts_with([' ', '\t'])
+            && !trimmed.starts_with(['}', ')', ']']);
+
+        let break_before = if top_level {
+            if self.lines.trailing_newlines >= 2 || self.lines.closed_block { 3 } else { 2 }
+        } else if self.lines.trailing_newlines >= 2 {
+            1
+        } else {
+            0
+        };
+
+        let depth_before = self.lines.depth;
+        for c in line.chars() {
+            match c {
+                '{' => self.lines.depth += 1,
+                '}' => self.lines.depth = self.lines.depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+        self.lines.closed_block = depth_before > 0 && self.lines.depth == 0;
+        self.lines.trailing_newlines = if blank { self.lines.trailing_newlines + 1 } else { 1 };
+
+        self.add_segment(line, break_before, false)
+    }
+
+    fn add_segment(&mut self, text: String, break_before: u8, atomic: bool) -> Result<()> {
+        let tokens = self.backend.count_tokens(&text)?;
+        if tokens <= self.target_tokens || atomic {
+            self.pending_tokens += tokens;
+            self.segments.push_back(Segment { text, tokens, break_before });
+            return Ok(());
+        }
+
+        // A single unit exceeds the budget: hard-split it
+        let mut rest = text.as_str();
+        let mut break_before = break_before;
+        while !rest.is_empty() {
+            let (split_idx, tokens) = longest_fitting_prefix(self.backend, rest, self.target_tokens)?;
+            self.pending_tokens += tokens;
+            self.segments.push_back(Segment { text: rest[..split_idx].to_string(), tokens, break_before });
+            rest = &rest[split_idx..];
+            break_before = 0;
+        }
+        Ok(())
+    }
+
+    /// Binary search for the shortest suffix start (on char boundaries) whose
+    /// suffix fits `budget`. Returns `text.len()` if not even one char fits.
+    fn shortest_fitting_suffix(&self, text: &str, budget: usize) -> Result<usize> {
+        let boundaries: Vec<usize> = text.char_indices().map(|(i, _)| i).collect();
+        let mut left = 0;
+        let mut right = boundaries.len();
+        while left < right {
+            let mid = left + (right - left) / 2;
+            if self.backend.count_tokens(&text[boundaries[mid]..])? <= budget {
+                right = mid;
+            } else {
+                left = mid + 1;
             }
-            
-            // Search backwards for the last \n or period (。 or .)
-            let mut split_idx = best_idx;
-            let mut found_punct = false;
-            for i in (start_idx..best_idx).rev() {
-                if chars[i] == '\n' || chars[i] == '。' || chars[i] == '.' {
-                    split_idx = i + 1; // Include the punctuation
-                    found_punct = true;
-                    break;
+        }
+        Ok(boundaries.get(left).copied().unwrap_or(text.len()))
+    }
+
+    /// Trailing whole sentences of the previous chunk that fit the overlap
+    /// budget, or the tail of its last sentence if even that one is too long.
+    fn overlap_text(&self) -> Result<String> {
+        if self.overlap_tokens == 0 {
+            return Ok(String::new());
+        }
+
+        let mut start = self.previous.len();
+        let mut tokens = 0;
+        while start > 0 && tokens + self.previous[start - 1].tokens <= self.overlap_tokens {
+            start -= 1;
+            tokens += self.previous[start].tokens;
+        }
+
+        if start == self.previous.len() {
+            return match self.previous.last() {
+                Some(last) => {
+                    let suffix_start = self.shortest_fitting_suffix(&last.text, self.overlap_tokens)?;
+                    Ok(last.text[suffix_start..].to_string())
                 }
+                None => Ok(String::new()),
+            };
+        }
+        Ok(self.previous[start..].iter().map(|s| s.text.as_str()).collect())
+    }
+
+    /// Number of queued segments that form the next chunk: the longest run that
+    /// fits the budget (at least one segment), cut before the strongest
+    /// boundary in its second half. Ties go to the later boundary, so prose
+    /// without paragraphs splits at the last sentence that fits.
+    fn next_chunk_len(&self) -> usize {
+        let mut cumulative = Vec::new();
+        let mut tokens = 0;
+        for segment in &self.segments {
+            if !cumulative.is_empty() && tokens + segment.tokens > self.target_tokens {
+                break;
             }
-            
-            // If no punctuation found, just split at best_idx
-            if found_punct {
-                best_idx = split_idx;
+            tokens += segment.tokens;
+            cumulative.push(tokens);
+        }
+
+        let fit = cumulative.len();
+        if fit == self.segments.len() {
+            return fit;
+        }
+
+        let mut best = fit;
+        let mut best_break = self.segments[fit].break_before;
+        for k in (1..fit).rev() {
+            if cumulative[k - 1] < self.target_tokens / 2 {
+                break;
+            }
+            if self.segments[k].break_before > best_break {
+                best = k;
+                best_break = self.segments[k].break_before;
+            }
+        }
+        best
+    }
+
+    /// Packs queued segments into chunks. Before EOF a chunk is only cut once
+    /// the queue holds more than one budget's worth, i.e. once we know the next
+    /// unit would not fit.
+    fn take_ready(&mut self, eof: bool) -> Result<Vec<ChunkTask>> {
+        let mut tasks = Vec::new();
+        while self.pending_tokens > self.target_tokens || (eof && !self.segments.is_empty()) {
+            let len = self.next_chunk_len();
+            let body: Vec<Segment> = self.segments.drain(..len).collect();
+            let body_tokens = body.iter().map(|s| s.tokens).sum::<usize>();
+            self.pending_tokens -= body_tokens;
+
+            let mut text = self.overlap_text()?;
+            let overlap = text.len();
+            for segment in &body {
+                text.push_str(&segment.text);
             }
+            let start = self.offset;
+            self.offset += text.len() - overlap;
+            tasks.push(ChunkTask {
+                index: self.next_index,
+                source: self.source.clone(),
+                text,
+                overlap,
+                byte_range: start..self.offset,
+                tokens: body_tokens,
+            });
+            self.next_index += 1;
+            self.previous = body;
+        }
+        Ok(tasks)
+    }
+}
+
+/// Binary search for the longest prefix (on char boundaries) that fits
+/// `budget`. Always takes at least one char so the split makes progress.
+pub(crate) fn longest_fitting_prefix(backend: &dyn InferenceBackend, text: &str, budget: usize) -> Result<(usize, usize)> {
+    let whole = backend.count_tokens(text)?;
+    if whole <= budget {
+        return Ok((text.len(), whole));
+    }
+
+    let boundaries: Vec<usize> = text
+        .char_indices()
+        .map(|(i, _)| i)
+        .skip(1)
+        .chain(std::iter::once(text.len()))
+        .collect();
+    let mut left = 0;
+    let mut right = boundaries.len() - 1;
+    let mut best = (boundaries[0], backend.count_tokens(&text[..boundaries[0]])?);
+    while left <= right {
+        let mid = left + (right - left) / 2;
+        let tokens = backend.count_tokens(&text[..boundaries[mid]])?;
+        if tokens <= budget {
+            best = (boundaries[mid], tokens);
+            left = mid + 1;
+        } else if mid == 0 {
+            break;
+        } else {
+            right = mid - 1;
+        }
+    }
+    Ok(best)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockBackend;
+
+    /// Chunks `text` pushed in one piece (one token per char).
+    fn chunk(mode: ChunkMode, target_tokens: usize, overlap_tokens: usize, text: &str) -> Vec<ChunkTask> {
+        let backend = MockBackend::echo();
+        let mut chunker = Chunker::new(&backend, mode, target_tokens, overlap_tokens);
+        let mut tasks = chunker.push(text).unwrap();
+        tasks.extend(chunker.finish().unwrap());
+        tasks
+    }
+
+    fn bodies(tasks: &[ChunkTask]) -> Vec<&str> {
+        tasks.iter().map(ChunkTask::body).collect()
+    }
+
+    /// Runs `parse_and_chunk` over `input`, read `READ_BUFFER_SIZE` bytes at a time.
+    fn parse(input: &[u8], options: &ChunkOptions) -> Vec<ChunkTask> {
+        let backend = MockBackend::echo();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        parse_and_chunk(&backend, input, None, 0, tx, options).unwrap();
+        rx.into_iter().collect()
+    }
+
+    /// Checks that the bodies tile `text` exactly and stay within `budget`.
+    fn assert_tiles(tasks: &[ChunkTask], text: &str, budget: usize) {
+        let mut offset = 0;
+        for task in tasks {
+            assert_eq!(task.byte_range.start, offset);
+            assert_eq!(&text[task.byte_range.clone()], task.body());
+            assert_eq!(task.tokens, task.body().chars().count());
+            assert!(task.tokens <= budget, "chunk {} has {} tokens", task.index, task.tokens);
+            offset = task.byte_range.end;
         }
-        
-        let chunk_str: String = chars[start_idx..best_idx].iter().collect();
-        worker_tx.send(ChunkTask {
-            index: chunk_index,
-            text: chunk_str,
-        }).expect("Worker disconnected");
-        
-        chunk_index += 1;
-        start_idx = best_idx;
+        assert_eq!(offset, text.len());
+    }
+
+    /// Like `assert_tiles`, for modes whose atomic units may exceed the budget.
+    fn assert_tiles_atomic(tasks: &[ChunkTask], text: &str) {
+        assert_tiles(tasks, text, usize::MAX);
+    }
+
+    #[test]
+    fn terminator_split_by_the_read_buffer() {
+        // The 。 of one sentence starts one byte before the end of the first read
+        let sentence = "吾輩は猫である。";
+        let text = format!("{}{}", "x".repeat(18), sentence.repeat(3000));
+        let end = text.match_indices('。').map(|(i, _)| i).find(|&i| i + 3 > READ_BUFFER_SIZE).unwrap();
+        assert_eq!(end, READ_BUFFER_SIZE - 1);
+
+        let tasks = parse(text.as_bytes(), &ChunkOptions { target_tokens: 100, ..ChunkOptions::default() });
+        assert_tiles(&tasks, &text, 100);
+        assert!(tasks.iter().all(|task| task.body().ends_with(sentence)));
+    }
+
+    #[test]
+    fn multibyte_character_split_by_the_read_buffer() {
+        let text = format!("{}{}", "ab".repeat(READ_BUFFER_SIZE / 2 - 1), "x猫。\n".repeat(100));
+        assert!(!text.is_char_boundary(READ_BUFFER_SIZE));
+
+        let tasks = parse(text.as_bytes(), &ChunkOptions { target_tokens: 500, ..ChunkOptions::default() });
+        assert_tiles(&tasks, &text, 500);
+        assert!(!tasks.iter().any(|task| task.text.contains('\u{FFFD}')));
+    }
+
+    #[test]
+    fn endless_line_is_cut_at_max_partial_bytes() {
+        let backend = MockBackend::echo();
+        let mut chunker = Chunker::new(&backend, ChunkMode::Prose, 1000, 0);
+        let piece = "x".repeat(10_000);
+        let mut streamed = Vec::new();
+        for _ in 0..MAX_PARTIAL_BYTES / piece.len() {
+            streamed.extend(chunker.push(&piece).unwrap());
+        }
+        // Nothing is known to end yet, so nothing is sent
+        assert!(streamed.is_empty());
+
+        streamed.extend(chunker.push(&piece).unwrap());
+        assert!(!streamed.is_empty(), "the partial text is flushed once it passes MAX_PARTIAL_BYTES");
+        streamed.extend(chunker.finish().unwrap());
+        let text = piece.repeat(MAX_PARTIAL_BYTES / piece.len() + 1);
+        assert_tiles(&streamed, &text, 1000);
+    }
+
+    #[test]
+    fn oversized_sentence_is_split_hard() {
+        let text = "あいうえおかきくけこさしすせそたちつてとなにぬねの。短い文。";
+        let tasks = chunk(ChunkMode::Prose, 10, 0, text);
+        assert_eq!(bodies(&tasks), ["あいうえおかきくけこ", "さしすせそたちつてと", "なにぬねの。短い文。"]);
+        assert_tiles(&tasks, text, 10);
+    }
+
+    #[test]
+    fn chunks_stay_within_the_budget() {
+        let text = "吾輩は猫である。名前はまだ無い。\n\nどこで生れたかとんと見当がつかぬ。何でも薄暗いじめじめした所でニャーニャー泣いていた事だけは記憶している。\n".repeat(20);
+        for budget in [5, 17, 40, 100] {
+            let tasks = chunk(ChunkMode::Prose, budget, 0, &text);
+            assert_tiles(&tasks, &text, budget);
+        }
+    }
+
+    #[test]
+    fn pushing_in_pieces_gives_the_same_chunks() {
+        let text = "吾輩は猫である。「名前はまだ無い」\n\nどこで生れたか。\n".repeat(10);
+        let whole = chunk(ChunkMode::Prose, 30, 0, &text);
+
+        let backend = MockBackend::echo();
+        let mut chunker = Chunker::new(&backend, ChunkMode::Prose, 30, 0);
+        let mut pieces = Vec::new();
+        for (i, c) in text.chars().enumerate() {
+            pieces.extend(chunker.push(&c.to_string()).unwrap());
+            if i % 7 == 0 {
+                pieces.extend(chunker.push("").unwrap());
+            }
+        }
+        pieces.extend(chunker.finish().unwrap());
+        assert_eq!(bodies(&pieces), bodies(&whole));
+        assert_tiles(&pieces, &text, 30);
+    }
+
+    #[test]
+    fn markdown_keeps_fenced_code_whole() {
+        let fence = format!("```rust\n{}```\n", "let x = 1;\n".repeat(10));
+        let text = format!("# Title\n\nSome text.\n\n{}\nMore text.\n", fence);
+        let tasks = chunk(ChunkMode::Markdown, 20, 0, &text);
+
+        assert!(tasks.iter().any(|task| task.body().contains(&fence)), "{:?}", bodies(&tasks));
+        for task in &tasks {
+            assert_eq!(task.body().matches("```").count() % 2, 0, "split inside the fence: {:?}", task.body());
+        }
+        assert_tiles_atomic(&tasks, &text);
+    }
+
+    #[test]
+    fn markdown_splits_before_headings() {
+        let text = "aaaaaaaaaa\nbbbbbbbbbb\n## Cccc\ndddddddddd\neeeeeeeeee\n";
+        let tasks = chunk(ChunkMode::Markdown, 40, 0, text);
+        // The heading would still fit, but starts the next chunk
+        assert_eq!(bodies(&tasks), ["aaaaaaaaaa\nbbbbbbbbbb\n", "## Cccc\ndddddddddd\neeeeeeeeee\n"]);
+    }
+
+    #[test]
+    fn code_splits_between_top_level_items() {
+        let item = |name: &str| format!("fn {}() {{\n    let x = 1;\n    let y = 2;\n}}\n", name);
+        let text: String = ["a", "b", "c", "d"].into_iter().map(item).collect();
+        // Room for one item and a half
+        let tasks = chunk(ChunkMode::Code, 60, 0, &text);
+        assert_eq!(tasks.len(), 4);
+        for task in &tasks {
+            assert!(task.body().starts_with("fn "), "{:?}", task.body());
+        }
+        assert_tiles(&tasks, &text, 60);
+
+        // An unindented line inside braces is not a top-level item
+        let lines = "    let x = 1;\n".repeat(4);
+        let text = format!("fn a() {{\n{}const INNER: u8 = 0;\n{}}}\n", lines, lines);
+        let tasks = chunk(ChunkMode::Code, 100, 0, &text);
+        assert!(tasks.len() > 1);
+        assert!(!tasks.iter().any(|task| task.body().starts_with("const")), "{:?}", bodies(&tasks));
+        assert_tiles(&tasks, &text, 100);
+    }
+
+    #[test]
+    fn jsonl_never_splits_a_record() {
+        let long = format!("{{\"message\": \"{}\"}}\n", "x".repeat(100));
+        let text = format!("{{\"a\": 1}}\n{}{{\"b\": 2}}\n{{\"c\": 3}}\n", long);
+        let tasks = chunk(ChunkMode::Jsonl, 30, 0, &text);
+
+        assert!(tasks.iter().any(|task| task.body() == long));
+        for task in &tasks {
+            assert!(task.body().ends_with('\n') && task.body().lines().all(|line| line.starts_with('{') && line.ends_with('}')));
+        }
+        assert_tiles_atomic(&tasks, &text);
+    }
+
+    #[test]
+    fn jsonl_waits_for_the_end_of_an_oversized_record() {
+        let backend = MockBackend::echo();
+        let mut chunker = Chunker::new(&backend, ChunkMode::Jsonl, 100, 0);
+        let record = format!("{{\"message\": \"{}\"}}", "x".repeat(MAX_PARTIAL_BYTES));
+        assert!(chunker.push(&record).unwrap().is_empty());
+
+        let mut tasks = chunker.push("\n{\"b\": 2}\n").unwrap();
+        tasks.extend(chunker.finish().unwrap());
+        assert_eq!(bodies(&tasks), [format!("{}\n", record).as_str(), "{\"b\": 2}\n"]);
+    }
+
+    #[test]
+    fn overlap_repeats_the_trailing_sentences() {
+        let text = "一二三四。五六七八。九十一二。三四五六。七八九十。一二三四。";
+        // Two sentences of five tokens fit an overlap of 10 or 14, not three
+        for overlap in [10, 14] {
+            let tasks = chunk(ChunkMode::Prose, 15, overlap, text);
+            assert_eq!(bodies(&tasks), ["一二三四。五六七八。九十一二。", "三四五六。七八九十。一二三四。"]);
+            assert_eq!(tasks[0].overlap_text(), "");
+            assert_eq!(tasks[1].overlap_text(), "五六七八。九十一二。");
+            assert_eq!(tasks[1].text, format!("{}{}", tasks[1].overlap_text(), tasks[1].body()));
+            // The overlap is not part of the chunk's own range or token count
+            assert_tiles(&tasks, text, 15);
+        }
+
+        // A last sentence longer than the overlap contributes its tail
+        let tasks = chunk(ChunkMode::Prose, 15, 3, text);
+        assert_eq!(tasks[1].overlap_text(), "一二。");
+    }
+
+    #[test]
+    fn closing_quote_ends_a_sentence() {
+        let tasks = chunk(ChunkMode::Prose, 10, 0, "「吾輩は猫である」と彼は言った。「名前はまだ無い」");
+        assert_eq!(bodies(&tasks), ["「吾輩は猫である」", "と彼は言った。", "「名前はまだ無い」"]);
+
+        // A closer after a terminator stays with it
+        let tasks = chunk(ChunkMode::Prose, 10, 0, "「猫である。」と言った。");
+        assert_eq!(bodies(&tasks), ["「猫である。」", "と言った。"]);
     }
 }
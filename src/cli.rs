@@ -1,31 +1,327 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use encoding_rs::Encoding;
+use lfm_cmd::chunker::ChunkMode;
+use lfm_cmd::config::{ReduceStrategy, DEFAULT_CHUNK_TOKENS, DEFAULT_WORKERS};
+use lfm_cmd::encoding::parse_label;
+use lfm_cmd::error::{Error, Result};
+use lfm_cmd::preprocess::Preprocess;
+use lfm_cmd::task::Task;
+use lfm_cmd::AppConfig;
+use std::fs;
 use std::path::PathBuf;
 
+use crate::output::{Emit, OutputFormat, Verbosity};
+
 /// A blazing fast, generic stream AI processing CLI tool using Metal & GGUF
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Without a task, the input is summarized
+    #[command(flatten)]
+    pub args: Args,
+}
+
+/// The task presets; each picks its own prompts, silence rules and stages.
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Summarize the input (the default when no task is named).
+    Summarize(Args),
+    /// Extract JSON matching --schema from every chunk and merge the results.
+    Extract(Args),
+    /// Label every chunk with one of --labels (no reduce).
+    Classify {
+        /// Comma-separated labels to choose from, e.g. `bug,feature,question`.
+        #[arg(long, value_delimiter = ',', required = true)]
+        labels: Vec<String>,
+        #[command(flatten)]
+        args: Args,
+    },
+    /// Translate the input chunk by chunk, in input order (no reduce).
+    Translate {
+        /// Target language, in words the model understands (e.g. `English`, `日本語`).
+        #[arg(long, default_value = "English")]
+        to: String,
+        #[command(flatten)]
+        args: Args,
+    },
+    /// Keep what every chunk says about a question, then answer it from that.
+    Ask {
+        /// The question to answer from the input.
+        question: String,
+        #[command(flatten)]
+        args: Args,
+    },
+}
+
+impl Cli {
+    /// The selected task and its options.
+    pub fn into_task(self) -> (Task, Args) {
+        match self.command {
+            None => (Task::Summarize, self.args),
+            Some(Command::Summarize(args)) => (Task::Summarize, args),
+            Some(Command::Extract(args)) => (Task::Extract, args),
+            Some(Command::Classify { labels, args }) => (Task::Classify { labels }, args),
+            Some(Command::Translate { to, args }) => (Task::Translate { language: to }, args),
+            Some(Command::Ask { question, args }) => (Task::Ask { question }, args),
+        }
+    }
+}
+
+/// Options shared by every task.
+#[derive(clap::Args, Debug)]
 pub struct Args {
+    /// Files, directories or glob patterns to process, each on its own.
+    /// Reads stdin when omitted.
+    #[arg(conflicts_with = "from")]
+    pub inputs: Vec<String>,
+
     /// Max tokens per chunk. Defines the "sweet spot" for context comprehension.
-    #[arg(short = 't', long, default_value_t = 512)]
+    #[arg(short = 't', long, default_value_t = DEFAULT_CHUNK_TOKENS)]
     pub tokens: usize,
 
+    /// Tokens of the previous chunk to repeat at the start of each chunk for context.
+    /// Overrides `chunk_overlap_tokens` from the config file.
+    #[arg(long)]
+    pub overlap: Option<usize>,
+
+    /// How to find chunk boundaries: sentences and paragraphs (prose), headings and
+    /// fenced blocks (markdown), top-level items (code) or whole records (jsonl, alias log).
+    /// Overrides `chunk_mode` from the config file.
+    #[arg(long, value_enum)]
+    pub chunk_mode: Option<ChunkMode>,
+
+    /// Input encoding (e.g. utf-8, shift_jis, euc-jp, utf-16le).
+    /// Detected from the BOM or the content when omitted.
+    #[arg(short = 'e', long, value_parser = parse_label)]
+    pub encoding: Option<&'static Encoding>,
+
+    /// Normalize the input before chunking: `aozora` strips Aozora Bunko ruby,
+    /// annotations and header/footer notes. Overrides `preprocess` from the config file.
+    #[arg(long, value_enum)]
+    pub preprocess: Option<Preprocess>,
+
+    /// With several inputs, also write a summary across all files.
+    /// Overrides `combined_summary` from the config file.
+    #[arg(long)]
+    pub combined_summary: bool,
+
+    /// Output format: `text`, or `ndjson` with one JSON object per event
+    /// (chunk results, silent chunks, summaries and run stats).
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+
+    /// JSON Schema file: every chunk result is generated as JSON matching it
+    /// (grammar-constrained). Overrides `schema` from the config file.
+    #[arg(long, value_name = "FILE")]
+    pub schema: Option<PathBuf>,
+
+    /// Merge JSON chunk results (concatenate, deduplicate, count) instead of
+    /// summarizing them with the model. Overrides `merge.enabled` from the config file.
+    #[arg(long)]
+    pub merge: bool,
+
+    /// Comma-separated fields that identify the same item when merging
+    /// (e.g. `host,message`). Overrides `merge.keys` from the config file.
+    #[arg(long, value_delimiter = ',', value_name = "FIELDS")]
+    pub merge_keys: Option<Vec<String>>,
+
+    /// Also write a prose overview of the merged result with the model.
+    /// Overrides `merge.overview` from the config file.
+    #[arg(long)]
+    pub merge_overview: bool,
+
+    /// Which results to write to stdout: chunk results and summaries (all),
+    /// chunk results only (chunks) or summaries only (summary).
+    #[arg(long, value_enum, default_value_t = Emit::All)]
+    pub emit: Emit,
+
+    /// Report progress on stderr (decoded inputs, meta prompt, intermediate reduces, run stats).
+    #[arg(short = 'v', long, conflicts_with = "quiet")]
+    pub verbose: bool,
+
+    /// Do not print warnings on stderr; errors are still reported.
+    #[arg(short = 'q', long)]
+    pub quiet: bool,
+
+    /// Print chunk results as soon as each worker finishes instead of in input order.
+    #[arg(long)]
+    pub unordered: bool,
+
+    /// How chunk results are reduced: one rolling buffer (rolling), groups
+    /// reduced in parallel, level by level (tree), or a summary refined chunk
+    /// by chunk in input order (refine). Overrides `reduce_strategy` from the config file.
+    /// Contexts of `main_ctx_size` opened: rolling -w + 1; tree -w + 2 during the
+    /// map and at most -w + 1 after it; refine 1. Rolling and tree also open a
+    /// `meta_ctx_size` context while the meta prompt is generated.
+    #[arg(long, value_enum)]
+    pub reduce_strategy: Option<ReduceStrategy>,
+
+    /// Results reduced together in one step of the tree reduce.
+    /// Overrides `reduce_fan_in` from the config file.
+    #[arg(long, value_name = "N")]
+    pub fan_in: Option<usize>,
+
+    /// Which part of the pipeline to run: map and reduce (all), the per-chunk
+    /// map only (map), or the reduce only over the results saved in `--from` (reduce).
+    #[arg(long, value_enum, default_value_t = Stage::All)]
+    pub stage: Stage,
+
+    /// NDJSON written by an earlier `--format ndjson` run; its `chunk` records
+    /// are reduced again with `--stage reduce`.
+    #[arg(long, value_name = "FILE", required_if_eq("stage", "reduce"))]
+    pub from: Option<PathBuf>,
+
     /// Number of parallel workers
-    #[arg(short = 'w', long, default_value_t = 2)]
+    #[arg(short = 'w', long, default_value_t = DEFAULT_WORKERS)]
     pub workers: usize,
 
-    /// Path to the GGUF model file. If not provided, uses the embedded LFM2.5 model.
-    #[arg(short = 'm', long)]
+    /// Path to the GGUF model file. If not provided, uses the embedded LFM2.5 model
+    /// (required unless built with the `embedded-model` feature).
+    #[arg(short = 'm', long, required = !cfg!(feature = "embedded-model"))]
     pub model: Option<PathBuf>,
 
-    /// System prompt
-    #[arg(
-        short = 'p',
-        long,
-        default_value = "提供されたテキストを解析・要約し3行で出力してください。"
-    )]
-    pub prompt: String,
+    /// System prompt (each task has its own default)
+    #[arg(short = 'p', long)]
+    pub prompt: Option<String>,
 
     /// Path to advanced JSON configuration file
     #[arg(short = 'c', long)]
     pub config: Option<PathBuf>,
 }
+
+/// The part of the map-reduce pipeline a run executes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Stage {
+    /// Map every chunk, then reduce the results.
+    #[default]
+    All,
+    /// Map every chunk and stop; nothing is summarized.
+    Map,
+    /// Reduce chunk results saved from an earlier run (`--from`).
+    Reduce,
+}
+
+impl Args {
+    /// The task preset, overridden by the config file, overridden by the flags.
+    pub fn app_config(&self, task: &Task) -> Result<AppConfig> {
+        let mut app_config = AppConfig::default();
+        task.configure(&mut app_config);
+        if let Some(ref config_path) = self.config {
+            let config_str = fs::read_to_string(config_path)
+                .map_err(|e| Error::Config(format!("failed to read {}: {}", config_path.display(), e)))?;
+            app_config = app_config
+                .with_overrides(&config_str)
+                .map_err(|e| Error::Config(format!("failed to parse {}: {}", config_path.display(), e)))?;
+        }
+        if let Some(overlap) = self.overlap {
+            app_config.chunk_overlap_tokens = overlap;
+        }
+        if let Some(chunk_mode) = self.chunk_mode {
+            app_config.chunk_mode = chunk_mode;
+        }
+        if let Some(preprocess) = self.preprocess {
+            app_config.preprocess = preprocess;
+        }
+        if self.combined_summary {
+            app_config.combined_summary = true;
+        }
+        if let Some(ref schema_path) = self.schema {
+            let schema_str = fs::read_to_string(schema_path)
+                .map_err(|e| Error::Config(format!("failed to read {}: {}", schema_path.display(), e)))?;
+            let schema = serde_json::from_str(&schema_str)
+                .map_err(|e| Error::Config(format!("failed to parse {}: {}", schema_path.display(), e)))?;
+            app_config.schema = Some(schema);
+        }
+        if self.merge {
+            app_config.merge.enabled = true;
+        }
+        if let Some(ref keys) = self.merge_keys {
+            app_config.merge.keys = keys.clone();
+        }
+        if self.merge_overview {
+            app_config.merge.overview = true;
+        }
+        if let Some(strategy) = self.reduce_strategy {
+            app_config.reduce_strategy = strategy;
+        }
+        if let Some(fan_in) = self.fan_in {
+            app_config.reduce_fan_in = fan_in;
+        }
+        if app_config.reduce_fan_in < 2 {
+            return Err(Error::Config("reduce_fan_in must be at least 2".to_string()));
+        }
+        Ok(app_config)
+    }
+
+    pub fn verbosity(&self) -> Verbosity {
+        if self.quiet {
+            Verbosity::Quiet
+        } else if self.verbose {
+            Verbosity::Verbose
+        } else {
+            Verbosity::Normal
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> (Task, Args) {
+        Cli::try_parse_from([&["lfm-cmd"], args].concat()).unwrap().into_task()
+    }
+
+    #[test]
+    fn config_overrides_the_preset_and_flags_override_the_config() {
+        let dir = std::env::temp_dir().join(format!("lfm-cmd-cli-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("config.json");
+        fs::write(&config_path, r#"{"worker_prompt_template": "FROM CONFIG {TEXT}", "chunk_overlap_tokens": 5, "reduce_strategy": "tree"}"#).unwrap();
+        let config_path = config_path.to_str().unwrap();
+
+        let (task, args) = parse(&["translate", "-m", "model.gguf", "-c", config_path]);
+        let config = args.app_config(&task).unwrap();
+        assert_eq!(config.worker_prompt_template, "FROM CONFIG {TEXT}");
+        // Fields the config file leaves out keep the preset
+        assert_eq!(config.overlap_text_template, lfm_cmd::task::TRANSLATE_OVERLAP_TEMPLATE);
+        assert!(config.silence.sentinels.is_empty());
+        assert_eq!((config.chunk_overlap_tokens, config.reduce_strategy), (5, ReduceStrategy::Tree));
+
+        let (task, args) = parse(&["translate", "-m", "model.gguf", "-c", config_path, "--overlap", "9", "--reduce-strategy", "rolling"]);
+        let config = args.app_config(&task).unwrap();
+        assert_eq!((config.chunk_overlap_tokens, config.reduce_strategy), (9, ReduceStrategy::Rolling));
+    }
+
+    #[test]
+    fn fan_in_below_two_is_a_config_error() {
+        let (task, args) = parse(&["-m", "model.gguf", "--fan-in", "1"]);
+        assert!(matches!(args.app_config(&task), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn input_named_like_a_task() {
+        let (task, args) = parse(&["summarize", "-m", "model.gguf", "notes.txt"]);
+        assert_eq!((task, args.inputs), (Task::Summarize, vec!["notes.txt".to_string()]));
+
+        // A task name is only a task as the first argument; after an option or
+        // an input it is a file like any other
+        let (task, args) = parse(&["-m", "model.gguf", "translate"]);
+        assert_eq!((task, args.inputs), (Task::Summarize, vec!["translate".to_string()]));
+        let (task, args) = parse(&["notes.txt", "summarize", "-m", "model.gguf"]);
+        assert_eq!((task, args.inputs), (Task::Summarize, vec!["notes.txt".to_string(), "summarize".to_string()]));
+        let (task, args) = parse(&["translate", "-m", "model.gguf", "--", "summarize"]);
+        assert_eq!((task, args.inputs), (Task::Translate { language: "English".to_string() }, vec!["summarize".to_string()]));
+    }
+
+    #[test]
+    fn task_options_reach_the_task() {
+        let (task, _) = parse(&["classify", "--labels", "bug,feature", "-m", "model.gguf"]);
+        assert_eq!(task, Task::Classify { labels: vec!["bug".to_string(), "feature".to_string()] });
+        let (task, args) = parse(&["ask", "誰が犯人か", "-m", "model.gguf", "novel.txt"]);
+        assert_eq!((task, args.inputs), (Task::Ask { question: "誰が犯人か".to_string() }, vec!["novel.txt".to_string()]));
+    }
+}
@@ -3,8 +3,21 @@
 // -----------------------------------------------------------------------------
 #![allow(dead_code)]
 
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 
+use crate::chunker::ChunkMode;
+use crate::merge::MergeConfig;
+use crate::preprocess::Preprocess;
+use crate::silence::SilenceRules;
+
+// Default Pipeline Settings
+pub const DEFAULT_CHUNK_TOKENS: usize = 512;
+pub const DEFAULT_WORKERS: usize = 2;
+pub const DEFAULT_FAN_IN: usize = 8;
+pub const DEFAULT_REDUCE_OUTPUT_TOKENS: usize = 8192;
+pub const DEFAULT_SYSTEM_PROMPT: &str = "提供されたテキストを解析・要約し3行で出力してください。";
+
 // Default Prompts
 pub const META_PROMPT_TEMPLATE: &str = "<|startoftext|><|im_start|>system\nあなたは優秀なプロンプトエンジニアです。<|im_end|>\n<|im_start|>user\n以下のテキスト断片を分析し、元のテキストのジャンル（小説、技術論文、システムログ、議事録など）を判定してください。\nその後、このテキスト全体を最も美しく構造化して要約するための「AIへの指示書（システムプロンプト）」を作成してください。\n出力は150文字以内の「指示書」のみとし、解説や挨拶は一切含めないでください。\n【テキスト断片】\n{TEXT}<|im_end|>\n<|im_start|>assistant\n";
 
@@ -12,8 +25,31 @@ pub const WORKER_PROMPT_TEMPLATE: &str = "<|startoftext|><|im_start|>system\n{SY
 
 pub const INTERMEDIATE_REDUCE_PROMPT: &str = "<|startoftext|><|im_start|>system\n{SYS_PROMPT}<|im_end|>\n<|im_start|>user\n以下のテキスト群を統合・圧縮して、重要なコンテキストを維持した新しい中間要約を生成してください。\n\n{TEXT}<|im_end|>\n<|im_start|>assistant\n";
 
+pub const REFINE_PROMPT_TEMPLATE: &str = "<|startoftext|><|im_start|>system\n{SYS_PROMPT}\nあなたは長いテキストを先頭から順に読み進めながら要約を更新していくAIです。<|im_end|>\n<|im_start|>user\nここまでの要約:\n{PREV_SUMMARY}\n\n以下はその続きのテキストです。話の流れや登場人物、出来事のつながりを保ったまま、続きの内容を反映した新しい要約を作成してください。出力は更新後の要約のみとしてください。\n\n{TEXT}<|im_end|>\n<|im_start|>assistant\n";
+
+pub const OVERLAP_TEXT_TEMPLATE: &str = "[前のチャンクからの文脈（参照のみ・要約しないこと）]\n{OVERLAP}\n[本文]\n{TEXT}";
+
+pub const CROSS_FILE_REDUCE_PROMPT: &str = "<|startoftext|><|im_start|>system\n{SYS_PROMPT}<|im_end|>\n<|im_start|>user\n以下は複数のファイルそれぞれの要約です。ファイル間の共通点と相違点を踏まえて統合し、全体の要約を作成してください。\n\n{TEXT}<|im_end|>\n<|im_start|>assistant\n";
+
+pub const STRUCTURED_SILENCE_PROMPT: &str = "出力の1行目は、特筆すべき内容があれば「NOTABLE: yes」、なければ「NOTABLE: no」としてください。「NOTABLE: no」の場合はそれ以外何も出力しないでください。";
+
 pub const FINAL_REDUCE_PROMPT: &str = "<|startoftext|><|im_start|>system\n{SYS_PROMPT}<|im_end|>\n<|im_start|>user\n以下の内容を統合し、最終的な全体要約を作成してください。\n\n{TEXT}<|im_end|>\n<|im_start|>assistant\n";
 
+/// How the chunk results are reduced into a summary.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ReduceStrategy {
+    /// One rolling buffer, compressed whenever it grows too large (serial).
+    #[default]
+    Rolling,
+    /// Groups of `reduce_fan_in` results are reduced in parallel, level by
+    /// level until few enough remain for the final summary: on one extra
+    /// context while the map is running, on up to one per worker after it.
+    Tree,
+    /// Each chunk is summarized together with the summary of the chunks
+    /// before it, in order on one context, without a separate reduce.
+    Refine,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
@@ -21,7 +57,34 @@ pub struct AppConfig {
     pub meta_ctx_size: u32,
     pub main_ctx_size: u32,
     pub max_generate_tokens: i32,
+    pub meta_generate_tokens: usize,
     pub batch_size_limit: usize,
+    /// Tokens of the previous chunk repeated at the start of each chunk (0 = disjoint chunks).
+    pub chunk_overlap_tokens: usize,
+    /// How the input is segmented before packing chunks (`prose`, `markdown`, `code`, `jsonl`).
+    pub chunk_mode: ChunkMode,
+    /// Input normalization before chunking (`none`, `aozora`).
+    pub preprocess: Preprocess,
+    /// With several input files, also summarize their summaries into one.
+    pub combined_summary: bool,
+    /// When a chunk result counts as "nothing to report".
+    pub silence: SilenceRules,
+    /// JSON Schema every chunk result (and summary) must follow; generation
+    /// is constrained by a grammar built from it (see `schema::Schema`).
+    pub schema: Option<serde_json::Value>,
+    /// Merge JSON chunk results deterministically instead of the LLM reduce.
+    pub merge: MergeConfig,
+    /// `rolling`, `tree` or `refine` (`merge` takes precedence).
+    pub reduce_strategy: ReduceStrategy,
+    /// Results reduced together in one step of the tree reduce (at least 2).
+    pub reduce_fan_in: usize,
+    /// Tokens of the reduce context kept free for the generated summary, and
+    /// the most a reduce step may generate.
+    pub reduce_output_tokens: usize,
+    /// Buffered tokens that trigger an intermediate reduce. Unset, it is
+    /// derived from the context size minus the reduce templates, the system
+    /// prompt and `reduce_output_tokens`.
+    pub reduce_threshold_tokens: Option<usize>,
     
     pub sample_temp: f32,
     pub sample_top_k: i32,
@@ -33,6 +96,13 @@ pub struct AppConfig {
     pub worker_prompt_template: String,
     pub intermediate_reduce_prompt: String,
     pub final_reduce_prompt: String,
+    /// Reduces the per-file summaries when `combined_summary` is set.
+    pub cross_file_reduce_prompt: String,
+    /// Worker prompt of the refine strategy from the second chunk on;
+    /// `{PREV_SUMMARY}` is the summary so far.
+    pub refine_prompt_template: String,
+    /// Replaces `{TEXT}` in the worker prompt when a chunk carries overlap.
+    pub overlap_text_template: String,
 }
 
 impl Default for AppConfig {
@@ -41,7 +111,19 @@ impl Default for AppConfig {
             meta_ctx_size: 8192,
             main_ctx_size: 32768,
             max_generate_tokens: 32768,
+            meta_generate_tokens: 150,
             batch_size_limit: 4096,
+            chunk_overlap_tokens: 0,
+            chunk_mode: ChunkMode::Prose,
+            preprocess: Preprocess::None,
+            combined_summary: false,
+            silence: SilenceRules::default(),
+            schema: None,
+            merge: MergeConfig::default(),
+            reduce_strategy: ReduceStrategy::Rolling,
+            reduce_fan_in: DEFAULT_FAN_IN,
+            reduce_output_tokens: DEFAULT_REDUCE_OUTPUT_TOKENS,
+            reduce_threshold_tokens: None,
             
             sample_temp: 0.2,
             sample_top_k: 50,
@@ -53,6 +135,29 @@ impl Default for AppConfig {
             worker_prompt_template: WORKER_PROMPT_TEMPLATE.to_string(),
             intermediate_reduce_prompt: INTERMEDIATE_REDUCE_PROMPT.to_string(),
             final_reduce_prompt: FINAL_REDUCE_PROMPT.to_string(),
+            cross_file_reduce_prompt: CROSS_FILE_REDUCE_PROMPT.to_string(),
+            refine_prompt_template: REFINE_PROMPT_TEMPLATE.to_string(),
+            overlap_text_template: OVERLAP_TEXT_TEMPLATE.to_string(),
         }
     }
 }
+
+impl AppConfig {
+    /// Positions one generation can use: the context size, or
+    /// `max_generate_tokens` if that is smaller.
+    pub fn context_tokens(&self) -> usize {
+        self.main_ctx_size.min(self.max_generate_tokens.max(0) as u32) as usize
+    }
+
+    /// This configuration with every field present in the JSON object `json`
+    /// replaced, the way a `--config` file is applied on top of a task preset.
+    pub fn with_overrides(&self, json: &str) -> serde_json::Result<AppConfig> {
+        let overrides: serde_json::Map<String, serde_json::Value> = serde_json::from_str(json)?;
+        let mut fields = match serde_json::to_value(self)? {
+            serde_json::Value::Object(fields) => fields,
+            _ => unreachable!("AppConfig serializes to an object"),
+        };
+        fields.extend(overrides);
+        serde_json::from_value(serde_json::Value::Object(fields))
+    }
+}
@@ -0,0 +1,260 @@
+use encoding_rs::{Decoder, DecoderResult, Encoding, EUC_JP, SHIFT_JIS, UTF_16BE, UTF_16LE, UTF_8};
+
+/// What the decoder ended up doing with the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeReport {
+    pub encoding: &'static Encoding,
+    /// Malformed byte sequences replaced with U+FFFD.
+    pub replacements: usize,
+}
+
+/// Streaming decoder that picks the input encoding from the first bytes that
+/// are not plain ASCII, unless an encoding was forced.
+///
+/// Pure ASCII decodes the same in every supported encoding, so detection is
+/// deferred until there is something to tell them apart; this keeps
+/// `tail -f app.log | lfm-cmd` streaming without waiting for a sample.
+pub struct InputDecoder {
+    decoder: Option<Decoder>,
+    encoding: Option<&'static Encoding>,
+    replacements: usize,
+    /// Input held back until the encoding is known.
+    pending: Vec<u8>,
+}
+
+impl InputDecoder {
+    /// `None` detects the encoding from a BOM or from the content.
+    pub fn new(encoding: Option<&'static Encoding>) -> Self {
+        Self {
+            decoder: encoding.map(|e| e.new_decoder_with_bom_removal()),
+            encoding,
+            replacements: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Appends the decoded text of `input` to `output`. Partial sequences at
+    /// the end of `input` are kept until the next call; pass `last` at EOF.
+    pub fn decode(&mut self, input: &[u8], output: &mut String, last: bool) {
+        if self.decoder.is_some() {
+            return self.feed(input, output, last);
+        }
+        if self.pending.is_empty() && !last && input.iter().all(is_ascii) {
+            // Safe to pass through: every byte is ASCII, hence valid UTF-8
+            output.push_str(std::str::from_utf8(input).unwrap_or_default());
+            return;
+        }
+
+        self.pending.extend_from_slice(input);
+        let sample = if last { &self.pending[..] } else { settled(&self.pending) };
+        if !last && sample.iter().all(is_ascii) {
+            // Only the tail is non-ASCII, and it may be a character cut off by the read
+            return;
+        }
+        let (encoding, bom_len) = detect(sample);
+        self.encoding = Some(encoding);
+        self.decoder = Some(encoding.new_decoder_without_bom_handling());
+        let pending = std::mem::take(&mut self.pending);
+        self.feed(&pending[bom_len..], output, last);
+    }
+
+    fn feed(&mut self, mut input: &[u8], output: &mut String, last: bool) {
+        let decoder = self.decoder.as_mut().unwrap();
+        loop {
+            output.reserve(decoder.max_utf8_buffer_length_without_replacement(input.len()).unwrap_or(input.len()));
+            let (result, read) = decoder.decode_to_string_without_replacement(input, output, last);
+            input = &input[read..];
+            match result {
+                DecoderResult::InputEmpty => break,
+                DecoderResult::OutputFull => continue,
+                DecoderResult::Malformed(_, _) => {
+                    output.push('\u{FFFD}');
+                    self.replacements += 1;
+                }
+            }
+        }
+    }
+
+    pub fn report(&self) -> DecodeReport {
+        DecodeReport {
+            encoding: self.encoding.unwrap_or(UTF_8),
+            replacements: self.replacements,
+        }
+    }
+}
+
+fn is_ascii(byte: &u8) -> bool {
+    (0x01..0x80).contains(byte)
+}
+
+/// The part of `sample` detection can rely on: up to its last ASCII byte, so
+/// a multi-byte character cut off by the end of a read (which looks like
+/// truncated UTF-8 in every encoding) is left out. A long run without ASCII
+/// only loses the length of the longest character.
+fn settled(sample: &[u8]) -> &[u8] {
+    let tail = sample.iter().rev().take_while(|&&b| b >= 0x80).count();
+    let cut = if tail > 64 { 4 } else { tail };
+    &sample[..sample.len() - cut]
+}
+
+/// Parses an encoding label such as `utf-8`, `shift_jis`, `euc-jp` or `utf-16le`
+/// (any WHATWG label is accepted).
+pub fn parse_label(label: &str) -> std::result::Result<&'static Encoding, String> {
+    Encoding::for_label(label.trim().as_bytes()).ok_or_else(|| format!("unknown encoding `{}`", label))
+}
+
+/// Guesses the encoding of `sample`, returning it with the length of its BOM.
+///
+/// A BOM always wins. Otherwise NUL bytes with a UTF-16-like layout are
+/// UTF-16 (checked first: NUL is valid UTF-8, so ASCII text saved as UTF-16
+/// would pass for it), valid UTF-8 is UTF-8, and the rest is whichever of
+/// Shift_JIS and EUC-JP decodes into the most plausible Japanese text.
+pub fn detect(sample: &[u8]) -> (&'static Encoding, usize) {
+    if let Some((encoding, bom_len)) = Encoding::for_bom(sample) {
+        return (encoding, bom_len);
+    }
+
+    if let Some(encoding) = detect_utf16(sample) {
+        return (encoding, 0);
+    }
+
+    match std::str::from_utf8(sample) {
+        Ok(_) => return (UTF_8, 0),
+        // Only a sequence cut off by the end of the sample
+        Err(e) if e.error_len().is_none() => return (UTF_8, 0),
+        Err(_) => {}
+    }
+
+    let best = [SHIFT_JIS, EUC_JP, UTF_8]
+        .into_iter()
+        .max_by_key(|encoding| japanese_score(&encoding.decode_without_bom_handling(sample).0))
+        .unwrap();
+    (best, 0)
+}
+
+/// Text files without a BOM still contain newlines and spaces, which UTF-16
+/// encodes with a NUL high byte, so the side with more NULs tells LE from
+/// BE. The other high bytes of Japanese text fall in a few narrow ranges
+/// (kana and punctuation, CJK ideographs, full-width forms).
+fn detect_utf16(sample: &[u8]) -> Option<&'static Encoding> {
+    let pairs = sample.len() / 2;
+    if pairs == 0 || !sample.contains(&0) {
+        return None;
+    }
+    let bytes = |parity: usize| sample.iter().skip(parity).step_by(2);
+    let zeros = |parity: usize| bytes(parity).filter(|&&b| b == 0).count();
+    let (encoding, parity) = if zeros(1) >= zeros(0) { (UTF_16LE, 1) } else { (UTF_16BE, 0) };
+    let high = bytes(parity).filter(|&&b| matches!(b, 0x00 | 0x20 | 0x30 | 0x4E..=0x9F | 0xFF)).count();
+    (high * 10 >= pairs * 8).then_some(encoding)
+}
+
+/// Higher for text that looks like Japanese: kana, ideographs and full-width
+/// punctuation count for, half-width katakana (what EUC-JP looks like when
+/// read as Shift_JIS) and replacement characters count against.
+fn japanese_score(text: &str) -> i64 {
+    text.chars()
+        .map(|c| match c {
+            '\u{3000}'..='\u{30FF}' | '\u{4E00}'..='\u{9FFF}' | '\u{FF01}'..='\u{FF5E}' => 2,
+            '\u{FF61}'..='\u{FF9F}' => -1,
+            '\u{FFFD}' => -10,
+            _ => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "ログを確認しました。エラーは発生していません。\n";
+
+    /// Decodes `prefix` + `text` encoded as `encoding`, split into two reads
+    /// right after the first byte of the first non-ASCII character.
+    fn decode_split(encoding: &'static Encoding, prefix: &str) -> (String, DecodeReport) {
+        let mut input = prefix.as_bytes().to_vec();
+        input.extend_from_slice(&encoding.encode(TEXT).0);
+        let split = prefix.len() + 1;
+        assert!(input[split - 1] >= 0x80, "the split must fall inside a character");
+
+        let mut decoder = InputDecoder::new(None);
+        let mut output = String::new();
+        decoder.decode(&input[..split], &mut output, false);
+        decoder.decode(&input[split..], &mut output, true);
+        (output, decoder.report())
+    }
+
+    #[test]
+    fn shift_jis_lead_byte_at_the_read_boundary() {
+        let prefix = "2024-05-01 12:00:00 INFO ".repeat(100);
+        let (output, report) = decode_split(SHIFT_JIS, &prefix);
+        assert_eq!(report, DecodeReport { encoding: SHIFT_JIS, replacements: 0 });
+        assert_eq!(output, prefix + TEXT);
+    }
+
+    #[test]
+    fn euc_jp_lead_byte_at_the_read_boundary() {
+        let (output, report) = decode_split(EUC_JP, "INFO ");
+        assert_eq!(report, DecodeReport { encoding: EUC_JP, replacements: 0 });
+        assert_eq!(output, format!("INFO {}", TEXT));
+    }
+
+    #[test]
+    fn utf8_sequence_split_by_the_read_boundary() {
+        let (output, report) = decode_split(UTF_8, "INFO ");
+        assert_eq!(report, DecodeReport { encoding: UTF_8, replacements: 0 });
+        assert_eq!(output, format!("INFO {}", TEXT));
+    }
+
+    #[test]
+    fn shift_jis_split_in_every_position() {
+        let input = SHIFT_JIS.encode(TEXT).0;
+        for split in 1..input.len() {
+            let mut decoder = InputDecoder::new(None);
+            let mut output = String::new();
+            decoder.decode(&input[..split], &mut output, false);
+            decoder.decode(&input[split..], &mut output, true);
+            assert_eq!(decoder.report().encoding, SHIFT_JIS, "split at {}", split);
+            assert_eq!(output, TEXT, "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn ascii_passes_through_before_detection() {
+        let mut decoder = InputDecoder::new(None);
+        let mut output = String::new();
+        decoder.decode(b"plain ascii\n", &mut output, false);
+        assert_eq!(output, "plain ascii\n");
+    }
+
+    #[test]
+    fn bom_selects_utf16() {
+        let mut input = vec![0xFF, 0xFE];
+        input.extend(TEXT.encode_utf16().flat_map(u16::to_le_bytes));
+        let mut decoder = InputDecoder::new(None);
+        let mut output = String::new();
+        decoder.decode(&input[..3], &mut output, false);
+        decoder.decode(&input[3..], &mut output, true);
+        assert_eq!(decoder.report().encoding, UTF_16LE);
+        assert_eq!(output, TEXT);
+    }
+
+    #[test]
+    fn ascii_utf16_without_bom_is_not_utf8() {
+        for (input, encoding) in [(&b"h\0e\0l\0l\0o\0\n\0"[..], UTF_16LE), (&b"\0h\0e\0l\0l\0o\0\n"[..], UTF_16BE)] {
+            let mut decoder = InputDecoder::new(None);
+            let mut output = String::new();
+            decoder.decode(&input[..5], &mut output, false);
+            decoder.decode(&input[5..], &mut output, true);
+            assert_eq!(decoder.report().encoding, encoding);
+            assert_eq!(output, "hello\n");
+        }
+    }
+
+    #[test]
+    fn japanese_utf16_without_bom() {
+        let le: Vec<u8> = TEXT.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let be: Vec<u8> = TEXT.encode_utf16().flat_map(u16::to_be_bytes).collect();
+        assert_eq!(detect(&le), (UTF_16LE, 0));
+        assert_eq!(detect(&be), (UTF_16BE, 0));
+    }
+}
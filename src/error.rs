@@ -0,0 +1,72 @@
+use std::fmt;
+use std::io;
+
+/// Process exit codes used by the `lfm-cmd` binary.
+pub mod exit_code {
+    /// At least one chunk result or summary was written.
+    pub const SUCCESS: u8 = 0;
+    /// The run completed, but every chunk was silent (like `grep` with no match).
+    pub const SILENT: u8 = 1;
+    /// Invalid arguments or configuration file.
+    pub const USAGE: u8 = 2;
+    /// Reading the input or writing the output failed.
+    pub const IO: u8 = 3;
+    /// The llama backend could not be initialized or the model failed to load.
+    pub const MODEL_LOAD: u8 = 4;
+    /// Context creation, tokenization or decoding failed during the run.
+    pub const INFERENCE: u8 = 5;
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Invalid configuration (bad JSON, impossible sizes, ...).
+    Config(String),
+    /// Reading input or writing output failed.
+    Io(io::Error),
+    /// The backend could not be initialized or the model could not be loaded.
+    ModelLoad(String),
+    /// Context creation, tokenization, decoding or sampling failed.
+    Inference(String),
+    /// A pipeline thread panicked; the payload names the stage.
+    ThreadPanicked(&'static str),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Config(_) => exit_code::USAGE,
+            Error::Io(_) => exit_code::IO,
+            Error::ModelLoad(_) => exit_code::MODEL_LOAD,
+            Error::Inference(_) | Error::ThreadPanicked(_) => exit_code::INFERENCE,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(msg) => write!(f, "invalid configuration: {}", msg),
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::ModelLoad(msg) => write!(f, "failed to load model: {}", msg),
+            Error::Inference(msg) => write!(f, "inference failed: {}", msg),
+            Error::ThreadPanicked(stage) => write!(f, "{} thread panicked", stage),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
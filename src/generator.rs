@@ -0,0 +1,173 @@
+use llama_cpp_2::context::params::LlamaContextParams;
+use llama_cpp_2::context::LlamaContext;
+use llama_cpp_2::llama_backend::LlamaBackend;
+use llama_cpp_2::llama_batch::LlamaBatch;
+use llama_cpp_2::model::LlamaModel;
+use llama_cpp_2::sampling::LlamaSampler;
+use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+use std::num::NonZeroU32;
+use crate::backend::InferenceSession;
+use crate::config::AppConfig;
+use crate::error::{Error, Result};
+
+/// Sampling and length limits for a single `InferenceSession::generate` call.
+#[derive(Debug, Clone)]
+pub struct GenerationParams {
+    pub temp: f32,
+    pub top_k: i32,
+    pub top_p: f32,
+    pub penalty_repeat: f32,
+    pub penalty_last_n: i32,
+    pub seed: u32,
+    /// Upper bound on the absolute KV position (prompt + generated tokens).
+    pub max_position: i32,
+    /// Optional cap on the number of newly generated tokens.
+    pub max_new_tokens: Option<usize>,
+    /// GBNF grammar (start rule `root`) every completion must follow.
+    pub grammar: Option<String>,
+}
+
+impl GenerationParams {
+    pub fn from_config(config: &AppConfig) -> Self {
+        Self {
+            temp: config.sample_temp,
+            top_k: config.sample_top_k,
+            top_p: config.sample_top_p,
+            penalty_repeat: config.penalty_repeat,
+            penalty_last_n: config.penalty_last_n,
+            seed: 1234,
+            max_position: config.max_generate_tokens,
+            max_new_tokens: None,
+            grammar: None,
+        }
+    }
+
+    pub fn with_max_new_tokens(mut self, max_new_tokens: usize) -> Self {
+        self.max_new_tokens = Some(max_new_tokens);
+        self
+    }
+
+    pub fn with_grammar(mut self, grammar: impl Into<String>) -> Self {
+        self.grammar = Some(grammar.into());
+        self
+    }
+
+    fn sampler(&self, model: &LlamaModel) -> Result<LlamaSampler> {
+        let mut samplers = Vec::with_capacity(6);
+        // The grammar goes first so the other samplers only see tokens it allows
+        if let Some(grammar) = &self.grammar {
+            let grammar = LlamaSampler::grammar(model, grammar, "root")
+                .map_err(|e| Error::Config(format!("invalid grammar: {}", e)))?;
+            samplers.push(grammar);
+        }
+        samplers.extend([
+            LlamaSampler::temp(self.temp),
+            LlamaSampler::top_k(self.top_k),
+            LlamaSampler::top_p(self.top_p, 1),
+            LlamaSampler::penalties(self.penalty_last_n, self.penalty_repeat, 0.05, 0.05),
+            LlamaSampler::dist(self.seed),
+        ]);
+        Ok(LlamaSampler::chain_simple(samplers))
+    }
+}
+
+/// Owns one `LlamaContext` and runs prompt prefill + sampling on it.
+/// This is the llama.cpp implementation of `InferenceSession`.
+pub struct Generator<'a> {
+    model: &'a LlamaModel,
+    ctx: LlamaContext<'a>,
+    batch_size: usize,
+}
+
+impl<'a> Generator<'a> {
+    pub fn new(model: &'a LlamaModel, backend: &LlamaBackend, n_ctx: u32, batch_size: usize) -> Result<Self> {
+        let n_ctx = NonZeroU32::new(n_ctx)
+            .ok_or_else(|| Error::Config("context size must be greater than zero".to_string()))?;
+        if batch_size == 0 {
+            return Err(Error::Config("batch_size_limit must be greater than zero".to_string()));
+        }
+        let ctx_params = LlamaContextParams::default()
+            .with_n_ctx(Some(n_ctx))
+            .with_n_batch(batch_size as u32)
+            .with_n_ubatch(batch_size as u32);
+        let ctx = model
+            .new_context(backend, ctx_params)
+            .map_err(|e| Error::Inference(format!("failed to create context: {}", e)))?;
+
+        Ok(Self { model, ctx, batch_size })
+    }
+}
+
+impl InferenceSession for Generator<'_> {
+    /// Generates a completion for `prompt`, passing every decoded piece to `sink`
+    /// as it is produced. Returns the full generated text.
+    fn generate(&mut self, prompt: &str, params: &GenerationParams, sink: &mut dyn FnMut(&str)) -> Result<String> {
+        // Clear the cache from the previous call to prevent overflow and overlap
+        self.ctx.clear_kv_cache();
+
+        let tokens = self.model
+            .str_to_token(prompt, llama_cpp_2::model::AddBos::Always)
+            .map_err(|e| Error::Inference(format!("failed to tokenize prompt: {}", e)))?;
+
+        // Prefill the prompt in batches of at most `batch_size` tokens
+        let mut n_eval = 0;
+        let mut last_batch_tokens = 0;
+        while n_eval < tokens.len() {
+            let chunk_size = std::cmp::min(tokens.len() - n_eval, self.batch_size);
+            let mut batch = LlamaBatch::new(chunk_size, 1);
+            for i in 0..chunk_size {
+                let is_last = (n_eval + i) == (tokens.len() - 1);
+                batch.add(tokens[n_eval + i], (n_eval + i) as i32, &[0], is_last)
+                    .map_err(|e| Error::Inference(format!("failed to add to batch: {}", e)))?;
+            }
+            self.ctx.decode(&mut batch)
+                .map_err(|e| Error::Inference(format!("failed to decode prompt chunk: {}", e)))?;
+            last_batch_tokens = chunk_size;
+            n_eval += chunk_size;
+        }
+
+        let mut batch = LlamaBatch::new(1, 1);
+        let mut n_cur = tokens.len() as i32;
+        let mut n_generated = 0;
+        let mut decoder = encoding_rs::UTF_8.new_decoder();
+        let mut generated_text = String::new();
+
+        let mut sampler = params.sampler(self.model)?;
+        // Isolate history: only penalize newly generated tokens, not the input prompt.
+        // sampler.accept_many(&tokens);
+
+        loop {
+            if params.max_new_tokens.is_some_and(|max| n_generated >= max) {
+                break;
+            }
+
+            let candidates = self.ctx.candidates_ith(last_batch_tokens as i32 - 1);
+            last_batch_tokens = 1;
+            let mut candidates_p = LlamaTokenDataArray::from_iter(candidates, false);
+
+            candidates_p.apply_sampler(&mut sampler);
+            let new_token_id = candidates_p.selected_token()
+                .ok_or_else(|| Error::Inference("sampler selected no token".to_string()))?;
+            sampler.accept(new_token_id);
+
+            // Check if end of generation
+            if new_token_id == self.model.token_eos() || n_cur >= params.max_position {
+                break;
+            }
+
+            let token_str = crate::types::decode_token(self.model, new_token_id, &mut decoder);
+            sink(&token_str);
+            generated_text.push_str(&token_str);
+
+            batch.clear();
+            batch.add(new_token_id, n_cur, &[0], true)
+                .map_err(|e| Error::Inference(format!("failed to add to batch: {}", e)))?;
+            self.ctx.decode(&mut batch)
+                .map_err(|e| Error::Inference(format!("failed to decode next token: {}", e)))?;
+            n_cur += 1;
+            n_generated += 1;
+        }
+
+        Ok(generated_text)
+    }
+}
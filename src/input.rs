@@ -0,0 +1,60 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+
+/// Expands command-line inputs into the list of files to summarize.
+///
+/// Files are taken as is, directories are walked recursively (skipping hidden
+/// entries) and anything else containing `*`, `?` or `[` is matched as a glob
+/// (so quoted patterns work without shell support). Directory contents and
+/// glob matches are sorted; a file named twice is only read once.
+pub fn expand_inputs<S: AsRef<str>>(args: &[S]) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for arg in args {
+        let arg = arg.as_ref();
+        let path = Path::new(arg);
+        if path.is_dir() {
+            walk_dir(path, &mut files)?;
+        } else if path.exists() {
+            files.push(path.to_path_buf());
+        } else if arg.contains(['*', '?', '[']) {
+            let pattern = glob::glob(arg).map_err(|e| Error::Config(format!("invalid pattern {}: {}", arg, e)))?;
+            let mut matched: Vec<PathBuf> = pattern
+                .filter_map(|entry| entry.ok())
+                .filter(|path| path.is_file())
+                .collect();
+            if matched.is_empty() {
+                return Err(Error::Config(format!("no files match {}", arg)));
+            }
+            matched.sort();
+            files.extend(matched);
+        } else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{}: no such file or directory", arg)).into());
+        }
+    }
+
+    let mut seen = HashSet::new();
+    files.retain(|path| seen.insert(path.clone()));
+    Ok(files)
+}
+
+fn walk_dir(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| !path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.')))
+        .collect();
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            walk_dir(&path, files)?;
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
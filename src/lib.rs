@@ -0,0 +1,31 @@
+//! Streaming map-reduce summarization over GGUF models.
+//!
+//! Build a [`Pipeline`] around an [`InferenceBackend`] (a llama.cpp model via
+//! [`LlamaInference`], or [`MockBackend`] for offline runs) and feed it any
+//! `Read`er; results arrive as typed [`PipelineEvent`]s.
+
+pub mod backend;
+pub mod chunker;
+pub mod config;
+pub mod encoding;
+pub mod error;
+pub mod generator;
+pub mod input;
+pub mod merge;
+pub mod mock;
+pub mod pipeline;
+pub mod preprocess;
+pub mod schema;
+pub mod silence;
+pub mod task;
+pub mod types;
+
+mod prompts;
+mod reducer;
+mod worker;
+
+pub use backend::{InferenceBackend, InferenceSession, LlamaInference};
+pub use config::AppConfig;
+pub use error::{Error, Result};
+pub use mock::MockBackend;
+pub use pipeline::{CompletenessReport, Pipeline, PipelineBuilder, PipelineEvent, PipelineOutput, RunStats};
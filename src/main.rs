@@ -1,27 +1,27 @@
 mod cli;
-mod types;
-mod config;
-mod prompts;
-mod worker;
-mod reducer;
-mod chunker;
+mod output;
 
 use clap::Parser;
-use crossbeam_channel::bounded;
-use llama_cpp_2::llama_backend::LlamaBackend;
 use llama_cpp_2::model::params::LlamaModelParams;
-use llama_cpp_2::model::LlamaModel;
-use std::fs;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::PathBuf;
+use std::process::ExitCode;
 use std::sync::Arc;
-use std::thread;
 
-use cli::Args;
-use config::AppConfig;
-use types::ChunkTask;
-use worker::worker_loop;
-use reducer::run_reducer;
-use chunker::parse_and_chunk;
+use cli::{Args, Cli, Stage};
+use lfm_cmd::config::ReduceStrategy;
+use lfm_cmd::error::{exit_code, Error, Result};
+use lfm_cmd::input::expand_inputs;
+use lfm_cmd::schema::Schema;
+use lfm_cmd::task::Task;
+use lfm_cmd::{LlamaInference, Pipeline, PipelineEvent};
+use output::{print_ndjson, read_ndjson_results, Diagnostics, OutputFormat, TextOutput};
 
+#[cfg(all(feature = "cpu", feature = "metal"))]
+compile_error!("the `cpu` and `metal` features are mutually exclusive: build with `--no-default-features --features cpu`");
+
+#[cfg(feature = "embedded-model")]
 static EMBEDDED_MODEL: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/LFM2.5-1.2B-Instruct-Q4_K_M.gguf"));
 
 extern "C" fn void_log(
@@ -30,93 +30,128 @@ extern "C" fn void_log(
     _user_data: *mut std::ffi::c_void,
 ) {}
 
-fn main() {
+fn main() -> ExitCode {
     // Suppress all llama-cpp logs (especially the noisy hardware logs on startup/decode)
     unsafe {
         llama_cpp_sys_2::llama_log_set(Some(void_log), std::ptr::null_mut());
     }
 
-    let args = Args::parse();
+    // Usage errors exit with code 2 from inside clap
+    let (task, args) = Cli::parse().into_task();
 
-    // Determine model path: extract embedded if not provided
-    let model_path = if let Some(ref path) = args.model {
-        path.clone()
-    } else {
-        let temp_dir = std::env::temp_dir();
-        let filename = format!("lfm2.5-1.2b-instruct-q4-v{}.gguf", env!("CARGO_PKG_VERSION"));
-        let extracted_path = temp_dir.join(&filename);
-        if !extracted_path.exists() {
-            fs::write(&extracted_path, EMBEDDED_MODEL)
-                .expect("Failed to write embedded model to tmp directory");
+    match run(task, args) {
+        Ok(true) => ExitCode::from(exit_code::SUCCESS),
+        Ok(false) => ExitCode::from(exit_code::SILENT),
+        Err(err) => {
+            eprintln!("lfm-cmd: {}", err);
+            ExitCode::from(err.exit_code())
         }
-        extracted_path
+    }
+}
+
+/// Runs the pipeline over the inputs, stdin or saved results. Returns whether any chunk result or summary was produced.
+fn run(task: Task, args: Args) -> Result<bool> {
+    // 1. Load AppConfig: the task preset, then the config file, then the flags
+    let app_config = args.app_config(&task)?;
+
+    // Resolve inputs and check the silence patterns and schema before loading the model so typos fail fast
+    app_config.silence.matcher()?;
+    if let Some(schema) = app_config.schema.clone() {
+        Schema::new(schema)?;
+    }
+    task.check(&app_config)?;
+    if args.stage == Stage::Reduce && !task.reduces() {
+        return Err(Error::Config("this task has no reduce stage".to_string()));
+    }
+    if args.stage == Stage::Reduce && app_config.reduce_strategy == ReduceStrategy::Refine && !app_config.merge.enabled {
+        return Err(Error::Config("the refine strategy cannot reduce saved results; use rolling or tree".to_string()));
+    }
+    if args.from.is_some() && args.stage != Stage::Reduce {
+        return Err(Error::Config("--from is only used with --stage reduce".to_string()));
+    }
+    let files = expand_inputs(&args.inputs)?;
+    let saved_results = match args.from {
+        Some(ref path) => {
+            let file = File::open(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+            let results = read_ndjson_results(BufReader::new(file))
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+            Some(results)
+        }
+        None => None,
     };
 
-    // 1. Init backend (Metal enabled by build.rs and features)
-    let backend = Arc::new(LlamaBackend::init().expect("Failed to initialize llama backend"));
+    // 2. Determine model path: extract embedded if not provided
+    let model_path = match args.model {
+        Some(ref path) => path.clone(),
+        None => extract_embedded_model()?,
+    };
 
-    // 2. Load model
+    // 3. Init backend (Metal enabled by build.rs and features) and load model
     let model_params = LlamaModelParams::default();
-    let model = LlamaModel::load_from_file(&backend, &model_path, &model_params)
-        .expect("Failed to load model from file");
-    let model = Arc::new(model);
-
-    // 2.5 Load AppConfig
-    let app_config = if let Some(ref config_path) = args.config {
-        let config_str = fs::read_to_string(config_path)
-            .expect(&format!("Failed to read config file from {:?}", config_path));
-        serde_json::from_str(&config_str)
-            .expect("Failed to parse config file as JSON")
-    } else {
-        AppConfig::default()
+    // CPU-only builds keep every layer off the GPU
+    #[cfg(feature = "cpu")]
+    let model_params = model_params.with_n_gpu_layers(0);
+    let backend = Arc::new(LlamaInference::load(&model_path, &model_params, app_config.batch_size_limit)?);
+
+    // 4. Run the map-reduce pipeline over the inputs (stdin when none are given)
+    let diagnostics = Diagnostics::new(args.verbosity());
+    let pipeline = Pipeline::builder(backend)
+        .config(app_config)
+        .workers(args.workers)
+        .chunk_tokens(args.tokens)
+        .system_prompt(args.prompt.unwrap_or_else(|| task.system_prompt().to_string()))
+        .encoding(args.encoding)
+        .ordered(!args.unordered)
+        .reduce(args.stage != Stage::Map && task.reduces())
+        .build();
+
+    let mut any_result = false;
+    // Translations read as one document, without chunk headers
+    let mut text_output = TextOutput::new(files.len() > 1, !matches!(task, Task::Translate { .. }));
+    let on_event = |event: PipelineEvent| {
+        diagnostics.report(&event);
+        if matches!(
+            event,
+            PipelineEvent::Chunk { .. }
+                | PipelineEvent::MergedResult { .. }
+                | PipelineEvent::SourceSummary { .. }
+                | PipelineEvent::FinalSummary { .. }
+        ) {
+            any_result = true;
+        }
+        if !args.emit.includes(&event, args.format) {
+            return;
+        }
+        match args.format {
+            OutputFormat::Text => text_output.print(event),
+            OutputFormat::Ndjson => print_ndjson(&event),
+        }
     };
-    let app_config = Arc::new(app_config);
-
-    // 3. Set up the queue for workers and reducer
-    let (worker_tx, worker_rx) = bounded::<ChunkTask>(args.workers * 2);
-    let (reducer_tx, reducer_rx) = bounded::<(usize, String)>(args.workers * 2);
-
-    let mut worker_handles = vec![];
-    for id in 0..args.workers {
-        let rx_clone = worker_rx.clone();
-        let tx_clone = reducer_tx.clone();
-        let model_clone = model.clone();
-        let backend_clone = backend.clone();
-        let system_prompt = args.prompt.clone();
-        let config_clone = app_config.clone();
-        worker_handles.push(thread::spawn(move || {
-            let outputs = worker_loop(id, model_clone, backend_clone, rx_clone, system_prompt, config_clone);
-            for out in outputs {
-                let _ = tx_clone.send(out);
-            }
-        }));
+    if let Some(results) = saved_results {
+        pipeline.stream_results(results, on_event)?;
+    } else if files.is_empty() {
+        pipeline.stream(io::stdin(), on_event)?;
+    } else {
+        pipeline.stream_files(&files, on_event)?;
     }
 
-    // Spawn Reducer Thread
-    let reducer_model = model.clone();
-    let reducer_backend = backend.clone();
-    let reducer_prompt = args.prompt.clone();
-    let reducer_config = app_config.clone();
-    let reducer_handle = thread::spawn(move || {
-        run_reducer(reducer_model, reducer_backend, reducer_prompt, reducer_rx, reducer_config);
-    });
-
-    // Drop original receivers
-    drop(worker_rx);
-
-    // 4. Smart Chunking pipeline (stdin)
-    parse_and_chunk(&model, worker_tx.clone(), args.tokens);
-
-    // Close channel so workers finish their tasks and exit
-    drop(worker_tx);
+    // safe Drop: ARC unrefs and llama_model_free / llama_free are called automatically.
+    Ok(any_result)
+}
 
-    for handle in worker_handles {
-        let _ = handle.join();
+#[cfg(feature = "embedded-model")]
+fn extract_embedded_model() -> Result<PathBuf> {
+    let temp_dir = std::env::temp_dir();
+    let filename = format!("lfm2.5-1.2b-instruct-q4-v{}.gguf", env!("CARGO_PKG_VERSION"));
+    let extracted_path = temp_dir.join(&filename);
+    if !extracted_path.exists() {
+        std::fs::write(&extracted_path, EMBEDDED_MODEL)?;
     }
-    
-    // Close reducer channel when workers are done
-    drop(reducer_tx);
-    let _ = reducer_handle.join();
-    
-    // safe Drop: ARC unrefs and llama_model_free / llama_free are called automatically.
+    Ok(extracted_path)
+}
+
+#[cfg(not(feature = "embedded-model"))]
+fn extract_embedded_model() -> Result<PathBuf> {
+    // clap already requires --model, but a missing path must not panic
+    Err(Error::Config("no model given: pass -m/--model, or build with the `embedded-model` feature".to_string()))
 }
@@ -0,0 +1,226 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+/// Deterministic merging of JSON chunk results in place of the LLM reduce.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MergeConfig {
+    /// Merge the chunk results (JSON, usually produced with `schema`) in
+    /// Rust instead of summarizing them with the model.
+    pub enabled: bool,
+    /// Fields that identify the same item when objects in arrays are
+    /// deduplicated (e.g. `["host", "message"]`). Items without any of them
+    /// are deduplicated by their whole value.
+    pub keys: Vec<String>,
+    /// Also write a prose overview of the merged result with the model
+    /// (using `final_reduce_prompt`).
+    pub overview: bool,
+}
+
+/// Accumulates JSON chunk results into one value.
+///
+/// Objects are merged field by field, arrays are concatenated and
+/// deduplicated, and other values keep the first non-null occurrence. Every
+/// object in an array gets `_count` (how often it was found) and `_chunks`
+/// (the chunk indices it was found in).
+pub struct Merger {
+    keys: Vec<String>,
+    root: Option<Node>,
+}
+
+enum Node {
+    Object(BTreeMap<String, Node>),
+    Array(Items),
+    Scalar(Value),
+}
+
+#[derive(Default)]
+struct Items {
+    items: Vec<Item>,
+    /// Identity of each item, see `Merger::identity`.
+    positions: HashMap<String, usize>,
+}
+
+struct Item {
+    node: Node,
+    count: usize,
+    chunks: Vec<usize>,
+}
+
+impl Merger {
+    pub fn new(keys: &[String]) -> Self {
+        Self { keys: keys.to_vec(), root: None }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
+
+    /// Merges the result of chunk `index`.
+    pub fn add(&mut self, index: usize, value: Value) {
+        match self.root.take() {
+            Some(mut root) => {
+                self.merge(&mut root, value, index);
+                self.root = Some(root);
+            }
+            None => self.root = Some(self.node(value, index)),
+        }
+    }
+
+    /// The merged value (`null` if nothing was added).
+    pub fn value(&self) -> Value {
+        self.root.as_ref().map_or(Value::Null, Node::to_value)
+    }
+
+    fn node(&self, value: Value, index: usize) -> Node {
+        match value {
+            Value::Object(fields) => {
+                Node::Object(fields.into_iter().map(|(key, value)| (key, self.node(value, index))).collect())
+            }
+            Value::Array(values) => {
+                let mut items = Items::default();
+                self.extend(&mut items, values, index);
+                Node::Array(items)
+            }
+            value => Node::Scalar(value),
+        }
+    }
+
+    fn merge(&self, node: &mut Node, value: Value, index: usize) {
+        match (node, value) {
+            (Node::Object(fields), Value::Object(values)) => {
+                for (key, value) in values {
+                    match fields.get_mut(&key) {
+                        Some(field) => self.merge(field, value, index),
+                        None => {
+                            let field = self.node(value, index);
+                            fields.insert(key, field);
+                        }
+                    }
+                }
+            }
+            (Node::Array(items), Value::Array(values)) => self.extend(items, values, index),
+            (node @ Node::Scalar(Value::Null), value) => *node = self.node(value, index),
+            // Keep the first value (or the first shape, on a type mismatch)
+            _ => {}
+        }
+    }
+
+    fn extend(&self, items: &mut Items, values: Vec<Value>, index: usize) {
+        for value in values {
+            let identity = self.identity(&value);
+            match items.positions.get(&identity) {
+                Some(&position) => {
+                    let item = &mut items.items[position];
+                    item.count += 1;
+                    if item.chunks.last() != Some(&index) {
+                        item.chunks.push(index);
+                    }
+                    self.merge(&mut item.node, value, index);
+                }
+                None => {
+                    items.positions.insert(identity, items.items.len());
+                    items.items.push(Item { node: self.node(value, index), count: 1, chunks: vec![index] });
+                }
+            }
+        }
+    }
+
+    /// The key fields of an object (strings trimmed and lowercased), or the
+    /// whole value when it has none of them.
+    fn identity(&self, value: &Value) -> String {
+        if let Value::Object(fields) = value {
+            if self.keys.iter().any(|key| fields.contains_key(key)) {
+                let key: Vec<Value> = self
+                    .keys
+                    .iter()
+                    .map(|key| match fields.get(key) {
+                        Some(Value::String(text)) => Value::String(text.trim().to_lowercase()),
+                        Some(value) => value.clone(),
+                        None => Value::Null,
+                    })
+                    .collect();
+                return Value::Array(key).to_string();
+            }
+        }
+        // Object keys are sorted, so equal values serialize the same way
+        value.to_string()
+    }
+}
+
+impl Node {
+    fn to_value(&self) -> Value {
+        match self {
+            Node::Object(fields) => Value::Object(fields.iter().map(|(key, node)| (key.clone(), node.to_value())).collect()),
+            Node::Array(items) => Value::Array(
+                items
+                    .items
+                    .iter()
+                    .map(|item| match item.node.to_value() {
+                        Value::Object(mut fields) => {
+                            fields.insert("_count".to_string(), item.count.into());
+                            fields.insert("_chunks".to_string(), item.chunks.clone().into());
+                            Value::Object(fields)
+                        }
+                        value => value,
+                    })
+                    .collect(),
+            ),
+            Node::Scalar(value) => value.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn merge(keys: &[&str], results: Vec<(usize, Value)>) -> Value {
+        let keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+        let mut merger = Merger::new(&keys);
+        for (index, value) in results {
+            merger.add(index, value);
+        }
+        merger.value()
+    }
+
+    #[test]
+    fn keyed_items_from_two_chunks_are_counted_once() {
+        let merged = merge(
+            &["host", "message"],
+            vec![
+                (3, json!({ "errors": [{ "host": "web1", "message": "Timeout", "level": null }, { "host": "db1", "message": "disk full" }] })),
+                (7, json!({ "errors": [{ "host": "WEB1 ", "message": "timeout", "level": "warn" }, { "host": "web1", "message": "timeout" }] })),
+            ],
+        );
+
+        assert_eq!(
+            merged,
+            json!({ "errors": [
+                { "host": "web1", "message": "Timeout", "level": "warn", "_count": 3, "_chunks": [3, 7] },
+                { "host": "db1", "message": "disk full", "_count": 1, "_chunks": [3] },
+            ] })
+        );
+    }
+
+    #[test]
+    fn items_without_keys_are_deduplicated_by_value() {
+        let merged = merge(
+            &["host"],
+            vec![(0, json!({ "tags": ["disk", { "name": "io" }] })), (1, json!({ "tags": ["disk", { "name": "io" }, "net"] }))],
+        );
+
+        assert_eq!(merged, json!({ "tags": ["disk", { "name": "io", "_count": 2, "_chunks": [0, 1] }, "net"] }));
+    }
+
+    #[test]
+    fn scalars_keep_the_first_value() {
+        let merged = merge(&[], vec![(0, json!({ "title": null, "level": 1 })), (1, json!({ "title": "猫", "level": "high" }))]);
+
+        assert_eq!(merged, json!({ "title": "猫", "level": 1 }));
+        assert!(Merger::new(&[]).is_empty());
+        assert_eq!(Merger::new(&[]).value(), Value::Null);
+    }
+}
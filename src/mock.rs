@@ -0,0 +1,134 @@
+// -----------------------------------------------------------------------------
+// Deterministic fake backend for exercising the pipeline without a GGUF model
+// -----------------------------------------------------------------------------
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use crate::backend::{InferenceBackend, InferenceSession};
+use crate::error::Result;
+use crate::generator::GenerationParams;
+
+/// How `MockBackend` splits text into tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockTokenizer {
+    /// One token per `char` (suits Japanese input).
+    Chars,
+    /// One token per whitespace-separated word.
+    Whitespace,
+}
+
+enum Responder {
+    Echo,
+    Scripted(Mutex<VecDeque<String>>),
+    Func(Box<dyn Fn(&str) -> String + Send + Sync>),
+}
+
+/// An `InferenceBackend` that never touches llama.cpp.
+///
+/// Responses are either the prompt itself (`echo`), a fixed script replayed in
+/// call order (`scripted`), or computed from the prompt (`with_responder`).
+pub struct MockBackend {
+    tokenizer: MockTokenizer,
+    responder: Responder,
+}
+
+impl MockBackend {
+    /// Answers every prompt with the prompt text.
+    pub fn echo() -> Self {
+        Self { tokenizer: MockTokenizer::Chars, responder: Responder::Echo }
+    }
+
+    /// Answers with `responses` in call order. Once exhausted, every further
+    /// call returns an empty string (which the worker treats as silent).
+    pub fn scripted<I, S>(responses: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let queue = responses.into_iter().map(Into::into).collect();
+        Self { tokenizer: MockTokenizer::Chars, responder: Responder::Scripted(Mutex::new(queue)) }
+    }
+
+    /// Answers with whatever `f` returns for the rendered prompt.
+    pub fn with_responder<F>(f: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        Self { tokenizer: MockTokenizer::Chars, responder: Responder::Func(Box::new(f)) }
+    }
+
+    pub fn tokenizer(mut self, tokenizer: MockTokenizer) -> Self {
+        self.tokenizer = tokenizer;
+        self
+    }
+
+    fn respond(&self, prompt: &str) -> String {
+        match &self.responder {
+            Responder::Echo => prompt.to_string(),
+            Responder::Scripted(queue) => queue.lock().unwrap().pop_front().unwrap_or_default(),
+            Responder::Func(f) => f(prompt),
+        }
+    }
+
+    /// Splits `text` into token pieces that concatenate back to `text`.
+    fn pieces<'t>(&self, text: &'t str) -> Vec<&'t str> {
+        match self.tokenizer {
+            MockTokenizer::Chars => text
+                .char_indices()
+                .map(|(i, c)| &text[i..i + c.len_utf8()])
+                .collect(),
+            MockTokenizer::Whitespace => {
+                // Each piece is a word plus its trailing whitespace
+                let mut pieces = Vec::new();
+                let mut start = 0;
+                let mut in_space = false;
+                for (i, c) in text.char_indices() {
+                    if c.is_whitespace() {
+                        in_space = true;
+                    } else if in_space {
+                        pieces.push(&text[start..i]);
+                        start = i;
+                        in_space = false;
+                    }
+                }
+                if start < text.len() {
+                    pieces.push(&text[start..]);
+                }
+                pieces
+            }
+        }
+    }
+}
+
+impl InferenceBackend for MockBackend {
+    fn tokenize(&self, text: &str) -> Result<Vec<i32>> {
+        Ok(match self.tokenizer {
+            MockTokenizer::Chars => text.chars().map(|c| c as i32).collect(),
+            MockTokenizer::Whitespace => text
+                .split_whitespace()
+                .map(|word| word.chars().fold(0i32, |h, c| h.wrapping_mul(31).wrapping_add(c as i32)))
+                .collect(),
+        })
+    }
+
+    fn new_session(&self, _n_ctx: u32) -> Result<Box<dyn InferenceSession + '_>> {
+        Ok(Box::new(MockSession { backend: self }))
+    }
+}
+
+struct MockSession<'a> {
+    backend: &'a MockBackend,
+}
+
+impl InferenceSession for MockSession<'_> {
+    fn generate(&mut self, prompt: &str, params: &GenerationParams, sink: &mut dyn FnMut(&str)) -> Result<String> {
+        let response = self.backend.respond(prompt);
+        let limit = params.max_new_tokens.unwrap_or(usize::MAX);
+
+        let mut generated_text = String::new();
+        for piece in self.backend.pieces(&response).into_iter().take(limit) {
+            sink(piece);
+            generated_text.push_str(piece);
+        }
+        Ok(generated_text)
+    }
+}
@@ -0,0 +1,465 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use lfm_cmd::types::{ChunkResult, ChunkStatus, Usage};
+use lfm_cmd::PipelineEvent;
+
+/// How results are written to stdout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Chunk results and summaries under `[Chunk N]`-style headers.
+    #[default]
+    Text,
+    /// One JSON object per event, for `jq` and other tools.
+    Ndjson,
+}
+
+/// Which results are written to stdout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Emit {
+    /// Chunk results and summaries.
+    #[default]
+    All,
+    /// Chunk results only.
+    Chunks,
+    /// Per-file and final summaries only.
+    Summary,
+}
+
+impl Emit {
+    /// Whether `event` belongs on stdout. Status events (meta prompt,
+    /// intermediate reduces, run stats) only appear there in NDJSON.
+    pub fn includes(self, event: &PipelineEvent, format: OutputFormat) -> bool {
+        match event {
+            PipelineEvent::Chunk { .. } | PipelineEvent::SilentChunk { .. } | PipelineEvent::InvalidChunk { .. } => {
+                self != Emit::Summary
+            }
+            PipelineEvent::MergedResult { .. }
+            | PipelineEvent::SourceSummaryDelta { .. }
+            | PipelineEvent::SourceSummary { .. }
+            | PipelineEvent::FinalSummaryDelta { .. }
+            | PipelineEvent::FinalSummary { .. } => self != Emit::Chunks,
+            _ => format == OutputFormat::Ndjson,
+        }
+    }
+}
+
+/// How much is reported on stderr besides errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Errors only.
+    Quiet,
+    /// Errors and warnings.
+    Normal,
+    /// Also progress: decoded inputs, meta prompt, intermediate reduces and run stats.
+    Verbose,
+}
+
+/// Writes warnings and progress lines to stderr, keeping stdout for results.
+pub struct Diagnostics {
+    verbosity: Verbosity,
+}
+
+impl Diagnostics {
+    pub fn new(verbosity: Verbosity) -> Self {
+        Self { verbosity }
+    }
+
+    pub fn report(&self, event: &PipelineEvent) {
+        match event {
+            PipelineEvent::InputDecoded { source, encoding, replacements } => {
+                if *replacements > 0 && self.verbosity >= Verbosity::Normal {
+                    eprintln!(
+                        "lfm-cmd: warning: replaced {} malformed byte sequence(s) with U+FFFD while decoding {} as {}",
+                        replacements,
+                        source_name(source),
+                        encoding
+                    );
+                } else if self.verbosity >= Verbosity::Verbose {
+                    eprintln!("lfm-cmd: read {} as {}", source_name(source), encoding);
+                }
+            }
+            PipelineEvent::InvalidChunk { index, errors, .. } if self.verbosity >= Verbosity::Normal => {
+                eprintln!("lfm-cmd: warning: chunk {} does not match the schema: {}", index, errors.join("; "));
+            }
+            PipelineEvent::MetaPromptFailed { error } if self.verbosity >= Verbosity::Normal => {
+                eprintln!("lfm-cmd: warning: meta-prompt generation failed, reducing with the system prompt: {}", error);
+            }
+            PipelineEvent::Completeness(report) if !report.is_complete() && self.verbosity >= Verbosity::Normal => {
+                eprintln!(
+                    "lfm-cmd: warning: left out of the reduce: failed chunks {:?}, missing chunks {:?}",
+                    report.failed, report.missing
+                );
+            }
+            _ if self.verbosity < Verbosity::Verbose => {}
+            PipelineEvent::MetaPrompt { text } => eprintln!("lfm-cmd: meta-prompt applied: {}", text),
+            PipelineEvent::IntermediateReduceStarted { index } => {
+                eprintln!("lfm-cmd: intermediate reduce {} triggered", index)
+            }
+            PipelineEvent::Completeness(report) => {
+                eprintln!("lfm-cmd: reduced {} chunks ({} silent)", report.reduced, report.silent)
+            }
+            PipelineEvent::RunStats(stats) => eprintln!(
+                "lfm-cmd: {} chunks ({} silent, {} invalid), {} intermediate reduces, {} input / {} output tokens in {:.1}s",
+                stats.chunks,
+                stats.silent_chunks,
+                stats.invalid_chunks,
+                stats.intermediate_reduces,
+                stats.input_tokens,
+                stats.output_tokens,
+                stats.elapsed.as_secs_f64()
+            ),
+            _ => {}
+        }
+    }
+}
+
+/// Prints events as human-readable text, streaming summaries as they are generated.
+pub struct TextOutput {
+    /// Whether chunk headers name their file.
+    multi_file: bool,
+    /// Whether chunk results get a `[Chunk N]` header at all.
+    chunk_headers: bool,
+    final_started: bool,
+    summary_source: Option<Option<Arc<Path>>>,
+}
+
+impl TextOutput {
+    pub fn new(multi_file: bool, chunk_headers: bool) -> Self {
+        Self { multi_file, chunk_headers, final_started: false, summary_source: None }
+    }
+
+    pub fn print(&mut self, event: PipelineEvent) {
+        match event {
+            PipelineEvent::Chunk { text, .. } if !self.chunk_headers => println!("{}", text),
+            PipelineEvent::Chunk { index, source, text, .. } => match source {
+                Some(path) if self.multi_file => println!("[Chunk {}: {}]\n{}", index, path.display(), text),
+                _ => println!("[Chunk {}]\n{}", index, text),
+            },
+            PipelineEvent::MergedResult { source, data } => {
+                // A `Value` always serializes
+                let data = serde_json::to_string_pretty(&data).unwrap_or_default();
+                match source {
+                    Some(path) => println!("\n[Merged: {}]\n{}", path.display(), data),
+                    None => println!("\n[Merged Result]\n{}", data),
+                }
+            }
+            PipelineEvent::SourceSummaryDelta { source, text } => {
+                if self.summary_source.as_ref() != Some(&source) {
+                    println!("\n[Summary: {}]", source_name(&source));
+                    self.summary_source = Some(source);
+                }
+                print!("{}", text);
+                let _ = io::stdout().flush();
+            }
+            PipelineEvent::SourceSummary { source, .. } => {
+                if self.summary_source.as_ref() != Some(&source) {
+                    println!("\n[Summary: {}]", source_name(&source));
+                }
+                self.summary_source = None;
+                println!();
+            }
+            PipelineEvent::FinalSummaryDelta { text } => {
+                if !self.final_started {
+                    println!("\n[Final Summary]");
+                    self.final_started = true;
+                }
+                print!("{}", text);
+                let _ = io::stdout().flush();
+            }
+            PipelineEvent::FinalSummary { .. } => {
+                if !self.final_started {
+                    println!("\n[Final Summary]");
+                }
+                println!();
+            }
+            PipelineEvent::InputDecoded { .. }
+            | PipelineEvent::SilentChunk { .. }
+            | PipelineEvent::InvalidChunk { .. }
+            | PipelineEvent::MetaPrompt { .. }
+            | PipelineEvent::MetaPromptFailed { .. }
+            | PipelineEvent::IntermediateReduceStarted { .. }
+            | PipelineEvent::IntermediateSummary { .. }
+            | PipelineEvent::Completeness(_)
+            | PipelineEvent::RunStats(_) => {}
+        }
+    }
+}
+
+/// Prints `event` as one line of NDJSON. Summary deltas are skipped: the
+/// complete text follows in the `source_summary` / `final_summary` record.
+pub fn print_ndjson(event: &PipelineEvent) {
+    if let Some(record) = Record::from_event(event) {
+        // Serializing plain strings and numbers cannot fail
+        println!("{}", serde_json::to_string(&record).unwrap_or_default());
+    }
+}
+
+/// The NDJSON form of a `PipelineEvent`.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record<'a> {
+    InputDecoded {
+        source: Option<Cow<'a, str>>,
+        encoding: &'a str,
+        replacements: usize,
+    },
+    Chunk {
+        index: usize,
+        source: Option<Cow<'a, str>>,
+        byte_range: [usize; 2],
+        #[serde(flatten)]
+        usage: UsageRecord,
+        text: &'a str,
+    },
+    SilentChunk {
+        index: usize,
+        source: Option<Cow<'a, str>>,
+        byte_range: [usize; 2],
+        #[serde(flatten)]
+        usage: UsageRecord,
+    },
+    InvalidChunk {
+        index: usize,
+        source: Option<Cow<'a, str>>,
+        byte_range: [usize; 2],
+        #[serde(flatten)]
+        usage: UsageRecord,
+        text: &'a str,
+        errors: &'a [String],
+    },
+    MetaPrompt {
+        text: &'a str,
+    },
+    MetaPromptFailed {
+        error: &'a str,
+    },
+    IntermediateReduceStarted {
+        index: usize,
+    },
+    IntermediateSummary {
+        index: usize,
+        #[serde(flatten)]
+        usage: UsageRecord,
+        text: &'a str,
+    },
+    Completeness {
+        reduced: usize,
+        silent: usize,
+        failed: &'a [usize],
+        missing: &'a [usize],
+    },
+    Merged {
+        source: Option<Cow<'a, str>>,
+        data: &'a serde_json::Value,
+    },
+    SourceSummary {
+        source: Option<Cow<'a, str>>,
+        #[serde(flatten)]
+        usage: UsageRecord,
+        text: &'a str,
+    },
+    FinalSummary {
+        #[serde(flatten)]
+        usage: UsageRecord,
+        text: &'a str,
+    },
+    RunStats {
+        chunks: usize,
+        silent_chunks: usize,
+        invalid_chunks: usize,
+        intermediate_reduces: usize,
+        input_tokens: usize,
+        output_tokens: usize,
+        elapsed_ms: u64,
+    },
+}
+
+#[derive(Serialize)]
+struct UsageRecord {
+    input_tokens: usize,
+    output_tokens: usize,
+    elapsed_ms: u64,
+}
+
+impl From<&Usage> for UsageRecord {
+    fn from(usage: &Usage) -> Self {
+        Self {
+            input_tokens: usage.input_tokens,
+            output_tokens: usage.output_tokens,
+            elapsed_ms: usage.elapsed.as_millis() as u64,
+        }
+    }
+}
+
+impl<'a> Record<'a> {
+    fn from_event(event: &'a PipelineEvent) -> Option<Self> {
+        let source = |source: &'a Option<Arc<Path>>| source.as_deref().map(Path::to_string_lossy);
+        Some(match event {
+            PipelineEvent::InputDecoded { source: path, encoding, replacements } => Record::InputDecoded {
+                source: source(path),
+                encoding,
+                replacements: *replacements,
+            },
+            PipelineEvent::Chunk { index, source: path, byte_range, usage, text } => Record::Chunk {
+                index: *index,
+                source: source(path),
+                byte_range: [byte_range.start, byte_range.end],
+                usage: usage.into(),
+                text,
+            },
+            PipelineEvent::SilentChunk { index, source: path, byte_range, usage } => Record::SilentChunk {
+                index: *index,
+                source: source(path),
+                byte_range: [byte_range.start, byte_range.end],
+                usage: usage.into(),
+            },
+            PipelineEvent::InvalidChunk { index, source: path, byte_range, usage, text, errors } => Record::InvalidChunk {
+                index: *index,
+                source: source(path),
+                byte_range: [byte_range.start, byte_range.end],
+                usage: usage.into(),
+                text,
+                errors,
+            },
+            PipelineEvent::MetaPrompt { text } => Record::MetaPrompt { text },
+            PipelineEvent::MetaPromptFailed { error } => Record::MetaPromptFailed { error },
+            PipelineEvent::IntermediateReduceStarted { index } => Record::IntermediateReduceStarted { index: *index },
+            PipelineEvent::IntermediateSummary { index, usage, text } => Record::IntermediateSummary {
+                index: *index,
+                usage: usage.into(),
+                text,
+            },
+            PipelineEvent::Completeness(report) => Record::Completeness {
+                reduced: report.reduced,
+                silent: report.silent,
+                failed: &report.failed,
+                missing: &report.missing,
+            },
+            PipelineEvent::MergedResult { source: path, data } => Record::Merged { source: source(path), data },
+            PipelineEvent::SourceSummary { source: path, usage, text } => Record::SourceSummary {
+                source: source(path),
+                usage: usage.into(),
+                text,
+            },
+            PipelineEvent::FinalSummary { usage, text } => Record::FinalSummary { usage: usage.into(), text },
+            PipelineEvent::RunStats(stats) => Record::RunStats {
+                chunks: stats.chunks,
+                silent_chunks: stats.silent_chunks,
+                invalid_chunks: stats.invalid_chunks,
+                intermediate_reduces: stats.intermediate_reduces,
+                input_tokens: stats.input_tokens,
+                output_tokens: stats.output_tokens,
+                elapsed_ms: stats.elapsed.as_millis() as u64,
+            },
+            PipelineEvent::SourceSummaryDelta { .. } | PipelineEvent::FinalSummaryDelta { .. } => return None,
+        })
+    }
+}
+
+/// Reads the chunk records (`chunk`, `silent_chunk`, `invalid_chunk`) back
+/// from NDJSON written by `print_ndjson`; every other record is skipped.
+pub fn read_ndjson_results(reader: impl BufRead) -> io::Result<Vec<ChunkResult>> {
+    let mut results = Vec::new();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", number + 1, e)))?;
+        let (index, source, status) = match record {
+            SavedRecord::Chunk { index, source, text } => (index, source, ChunkStatus::Output(text)),
+            SavedRecord::SilentChunk { index, source } => (index, source, ChunkStatus::Silent),
+            SavedRecord::InvalidChunk { index, source } => (index, source, ChunkStatus::Failed),
+            SavedRecord::Other => continue,
+        };
+        let source = source.map(|path| Arc::from(Path::new(&path)));
+        results.push(ChunkResult { index, source, status });
+    }
+    Ok(results)
+}
+
+/// The fields of saved chunk records that the reducer needs.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum SavedRecord {
+    Chunk { index: usize, source: Option<String>, text: String },
+    SilentChunk { index: usize, source: Option<String> },
+    InvalidChunk { index: usize, source: Option<String> },
+    #[serde(other)]
+    Other,
+}
+
+pub fn source_name(source: &Option<Arc<Path>>) -> String {
+    match source {
+        Some(path) => path.display().to_string(),
+        None => "stdin".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lfm_cmd::{AppConfig, CompletenessReport, MockBackend, Pipeline};
+
+    /// Runs `input` once, keeping the NDJSON lines and the completeness report.
+    fn run(pipeline: &Pipeline, input: &str) -> (String, CompletenessReport) {
+        let mut ndjson = String::new();
+        let mut completeness = None;
+        pipeline
+            .stream(input.as_bytes(), |event| {
+                if let Some(record) = Record::from_event(&event) {
+                    ndjson.push_str(&serde_json::to_string(&record).unwrap());
+                    ndjson.push('\n');
+                }
+                if let PipelineEvent::Completeness(report) = event {
+                    completeness = Some(report);
+                }
+            })
+            .unwrap();
+        (ndjson, completeness.unwrap())
+    }
+
+    // The `--from` side of `run_results_reduces_saved_results` in tests/pipeline.rs,
+    // here because the NDJSON records belong to the binary
+    #[test]
+    fn saved_ndjson_reduces_with_the_same_accounting() {
+        let config = AppConfig {
+            meta_prompt_template: "META {TEXT}".to_string(),
+            worker_prompt_template: "CHUNK {TEXT}".to_string(),
+            final_reduce_prompt: "FINAL {TEXT}".to_string(),
+            schema: Some(serde_json::json!({ "type": "object", "required": ["topic"] })),
+            ..AppConfig::default()
+        };
+        let backend = MockBackend::with_responder(|prompt| match prompt.split_once(' ') {
+            Some(("CHUNK", text)) if text.contains("名前") => "{\"topic\": \"特になし\"}".to_string(),
+            Some(("CHUNK", text)) if text.contains("生れ") => "{\"name\": null}".to_string(),
+            Some(("CHUNK", text)) => format!("{{\"topic\": \"{}\"}}", text.trim()),
+            Some(("META", _)) => "要約してください".to_string(),
+            _ => "{\"topic\": \"全体\"}".to_string(),
+        });
+        let pipeline = Pipeline::builder(Arc::new(backend)).config(config).chunk_tokens(12).workers(2).build();
+        let input = "吾輩は猫である。\n名前はまだ無い。\nどこで生れたか。\nとんと見当がつかぬ。\n";
+        let (ndjson, expected) = run(&pipeline, input);
+        assert_eq!((expected.reduced, expected.silent, expected.failed.as_slice()), (2, 1, [2].as_slice()));
+
+        let results = read_ndjson_results(ndjson.as_bytes()).unwrap();
+        let statuses: Vec<_> = results.iter().map(|result| (result.index, result.status.clone())).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                (0, ChunkStatus::Output("{\"topic\": \"吾輩は猫である。\"}".to_string())),
+                (1, ChunkStatus::Silent),
+                (2, ChunkStatus::Failed),
+                (3, ChunkStatus::Output("{\"topic\": \"とんと見当がつかぬ。\"}".to_string())),
+            ]
+        );
+        let report = pipeline.run_results(results).unwrap().completeness.unwrap();
+        assert_eq!(report, expected);
+    }
+}
@@ -0,0 +1,532 @@
+use crossbeam_channel::{bounded, unbounded};
+use encoding_rs::Encoding;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::backend::InferenceBackend;
+use crate::chunker::{parse_and_chunk, ChunkOptions};
+use crate::config::*;
+use crate::error::{Error, Result};
+use crate::reducer::{ReducerInput, run_reducer};
+use crate::types::{ChunkResult, ChunkTask, Usage};
+use crate::worker::{refine_loop, worker_loop};
+
+/// Results produced while the pipeline runs, in the order they become available.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PipelineEvent {
+    /// An input (`source`, `None` for stdin) was read completely;
+    /// `replacements` malformed byte sequences were replaced with U+FFFD
+    /// while decoding it as `encoding`.
+    InputDecoded { source: Option<Arc<Path>>, encoding: &'static str, replacements: usize },
+    /// A non-silent map result for chunk `index` of `source`.
+    Chunk { index: usize, source: Option<Arc<Path>>, byte_range: Range<usize>, usage: Usage, text: String },
+    /// Chunk `index` had nothing worth reporting.
+    SilentChunk { index: usize, source: Option<Arc<Path>>, byte_range: Range<usize>, usage: Usage },
+    /// The result for chunk `index` does not match `AppConfig::schema`
+    /// (e.g. it was cut off by the token limit); it is left out of the reduce.
+    InvalidChunk {
+        index: usize,
+        source: Option<Arc<Path>>,
+        byte_range: Range<usize>,
+        usage: Usage,
+        text: String,
+        errors: Vec<String>,
+    },
+    /// The system prompt generated from the first chunk summaries.
+    MetaPrompt { text: String },
+    /// The meta prompt could not be generated, so the reduce steps use the
+    /// system prompt as it is.
+    MetaPromptFailed { error: String },
+    /// The rolling buffer exceeded its budget and is being compressed.
+    IntermediateReduceStarted { index: usize },
+    IntermediateSummary { index: usize, usage: Usage, text: String },
+    /// How every chunk ended up in the reduce, sent once the map is done.
+    Completeness(CompletenessReport),
+    /// The chunk results merged with `AppConfig::merge`, once there is any
+    /// JSON result to merge: per input file (`source`) when several were
+    /// given, and for the whole run (`None`) unless several were given
+    /// without `AppConfig::combined_summary`.
+    MergedResult { source: Option<Arc<Path>>, data: serde_json::Value },
+    /// A piece of the summary of one input file, when several were given.
+    SourceSummaryDelta { source: Option<Arc<Path>>, text: String },
+    SourceSummary { source: Option<Arc<Path>>, usage: Usage, text: String },
+    /// A piece of the final summary, streamed as it is generated. With
+    /// several input files this is the optional cross-file summary.
+    FinalSummaryDelta { text: String },
+    FinalSummary { usage: Usage, text: String },
+    /// Totals for the whole run, sent last once every stage has finished.
+    RunStats(RunStats),
+}
+
+/// Totals over every generation step of a run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RunStats {
+    pub chunks: usize,
+    pub silent_chunks: usize,
+    pub invalid_chunks: usize,
+    pub intermediate_reduces: usize,
+    pub input_tokens: usize,
+    pub output_tokens: usize,
+    /// Wall time from the start of the run (the model is already loaded).
+    pub elapsed: Duration,
+}
+
+/// How the chunks of a run ended up in the reduce. Chunks in `failed` or
+/// `missing` were left out of every summary.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompletenessReport {
+    /// Chunks whose result was reduced.
+    pub reduced: usize,
+    pub silent: usize,
+    /// Chunks without a usable result (`InvalidChunk`s, failed generations).
+    pub failed: Vec<usize>,
+    /// Chunks whose result never reached the reducer (a worker panicked, or
+    /// they were left out of the results given to `stream_results`).
+    pub missing: Vec<usize>,
+}
+
+impl CompletenessReport {
+    /// Whether every chunk was either reduced or silent.
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty() && self.missing.is_empty()
+    }
+}
+
+/// Everything a finished run produced, collected from the `PipelineEvent`s.
+#[derive(Debug, Clone, Default)]
+pub struct PipelineOutput {
+    pub input_encoding: Option<&'static str>,
+    pub replacements: usize,
+    pub chunks: Vec<(usize, String)>,
+    pub silent_chunks: Vec<usize>,
+    /// Chunks whose result did not match the schema, with the violations.
+    pub invalid_chunks: Vec<(usize, Vec<String>)>,
+    pub meta_prompt: Option<String>,
+    pub intermediate_summaries: Vec<String>,
+    pub completeness: Option<CompletenessReport>,
+    pub source_summaries: Vec<(Option<Arc<Path>>, String)>,
+    pub merged_results: Vec<(Option<Arc<Path>>, serde_json::Value)>,
+    pub final_summary: Option<String>,
+    pub stats: RunStats,
+}
+
+/// One input of a run, opened by the chunker thread when its turn comes.
+enum Input<R> {
+    Reader(R),
+    File(Arc<Path>),
+}
+
+/// The map-reduce summarizer: chunking, parallel workers and the reducer
+/// (or a single refine worker, see `ReduceStrategy::Refine`).
+pub struct Pipeline {
+    backend: Arc<dyn InferenceBackend>,
+    config: Arc<AppConfig>,
+    workers: usize,
+    chunk_tokens: usize,
+    system_prompt: String,
+    encoding: Option<&'static Encoding>,
+    ordered: bool,
+    reduce: bool,
+}
+
+pub struct PipelineBuilder {
+    backend: Arc<dyn InferenceBackend>,
+    config: AppConfig,
+    workers: usize,
+    chunk_tokens: usize,
+    system_prompt: String,
+    encoding: Option<&'static Encoding>,
+    ordered: bool,
+    reduce: bool,
+}
+
+impl PipelineBuilder {
+    pub fn config(mut self, config: AppConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Number of parallel map workers (each opens its own context).
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    /// Max tokens per chunk.
+    pub fn chunk_tokens(mut self, chunk_tokens: usize) -> Self {
+        self.chunk_tokens = chunk_tokens;
+        self
+    }
+
+    pub fn system_prompt(mut self, system_prompt: impl Into<String>) -> Self {
+        self.system_prompt = system_prompt.into();
+        self
+    }
+
+    /// Input encoding; `None` (the default) detects it from a BOM or the content.
+    pub fn encoding(mut self, encoding: Option<&'static Encoding>) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Whether per-chunk events (`Chunk`, `SilentChunk`, `InvalidChunk`) are delivered in input order
+    /// (the default). Unordered delivery passes each result on as soon as its
+    /// worker finishes, at the cost of nondeterministic order with several workers.
+    pub fn ordered(mut self, ordered: bool) -> Self {
+        self.ordered = ordered;
+        self
+    }
+
+    /// Whether the chunk results are reduced into summaries (the default).
+    /// Without the reduce only the map runs: no meta prompt, no summaries.
+    pub fn reduce(mut self, reduce: bool) -> Self {
+        self.reduce = reduce;
+        self
+    }
+
+    pub fn build(self) -> Pipeline {
+        Pipeline {
+            backend: self.backend,
+            config: Arc::new(self.config),
+            workers: self.workers.max(1),
+            chunk_tokens: self.chunk_tokens,
+            system_prompt: self.system_prompt,
+            encoding: self.encoding,
+            ordered: self.ordered,
+            reduce: self.reduce,
+        }
+    }
+}
+
+impl Pipeline {
+    pub fn builder(backend: Arc<dyn InferenceBackend>) -> PipelineBuilder {
+        PipelineBuilder {
+            backend,
+            config: AppConfig::default(),
+            workers: DEFAULT_WORKERS,
+            chunk_tokens: DEFAULT_CHUNK_TOKENS,
+            system_prompt: DEFAULT_SYSTEM_PROMPT.to_string(),
+            encoding: None,
+            ordered: true,
+            reduce: true,
+        }
+    }
+
+    /// Runs the whole pipeline over `reader` and collects the results.
+    pub fn run<R: Read + Send>(&self, reader: R) -> Result<PipelineOutput> {
+        let mut output = PipelineOutput::default();
+        self.stream(reader, |event| output.collect(event))?;
+        Ok(output)
+    }
+
+    /// Runs the whole pipeline over several files and collects the results.
+    pub fn run_files(&self, paths: &[PathBuf]) -> Result<PipelineOutput> {
+        let mut output = PipelineOutput::default();
+        self.stream_files(paths, |event| output.collect(event))?;
+        Ok(output)
+    }
+
+    /// Runs the whole pipeline over `reader`, calling `on_event` on the
+    /// calling thread for every result as soon as it is available (chunk
+    /// results wait for their predecessors unless built with `ordered(false)`).
+    ///
+    /// Returns the first error raised by the chunker, a worker or the reducer.
+    pub fn stream<R, F>(&self, reader: R, on_event: F) -> Result<()>
+    where
+        R: Read + Send,
+        F: FnMut(PipelineEvent),
+    {
+        self.stream_inputs(vec![Input::Reader(reader)], on_event)
+    }
+
+    /// Like `stream`, over `paths` in order with one model load. Each file is
+    /// chunked separately; with more than one file every file gets its own
+    /// `SourceSummary`, and `AppConfig::combined_summary` adds a cross-file
+    /// `FinalSummary`.
+    pub fn stream_files<F>(&self, paths: &[PathBuf], on_event: F) -> Result<()>
+    where
+        F: FnMut(PipelineEvent),
+    {
+        let inputs = paths.iter().map(|path| Input::<io::Empty>::File(Arc::from(path.as_path()))).collect();
+        self.stream_inputs(inputs, on_event)
+    }
+
+    /// Runs only the reduce over chunk results from an earlier run (e.g. one
+    /// built with `reduce(false)`) and collects the results.
+    pub fn run_results(&self, results: Vec<ChunkResult>) -> Result<PipelineOutput> {
+        let mut output = PipelineOutput::default();
+        self.stream_results(results, |event| output.collect(event))?;
+        Ok(output)
+    }
+
+    /// Like `stream`, but skips chunking and the map: `results` go straight
+    /// to the reducer, so a reduce prompt can be tried again without paying
+    /// for the map. Results from more than one source are summarized per
+    /// source; indices missing from `results` are reported in `Completeness`.
+    pub fn stream_results<F>(&self, results: Vec<ChunkResult>, mut on_event: F) -> Result<()>
+    where
+        F: FnMut(PipelineEvent),
+    {
+        if self.refines() {
+            return Err(Error::Config("the refine strategy needs the chunks, not their results".to_string()));
+        }
+        let started = Instant::now();
+        let mut stats = RunStats::default();
+        let (event_tx, event_rx) = unbounded::<PipelineEvent>();
+        let per_source = results.iter().any(|result| result.source != results[0].source);
+
+        let (reducer_tx, reducer_rx) = unbounded::<ChunkResult>();
+        for result in results {
+            let _ = reducer_tx.send(result);
+        }
+        drop(reducer_tx);
+        // Only the results are known here, so missing indices are the gaps between them
+        let (_, total_rx) = bounded::<usize>(1);
+        let input = ReducerInput { results: reducer_rx, total: total_rx };
+
+        thread::scope(|s| {
+            let reducer_backend = self.backend.clone();
+            let reducer_prompt = self.system_prompt.clone();
+            let reducer_config = self.config.clone();
+            let workers = self.workers;
+            let reducer_handle = s.spawn(move || {
+                run_reducer(reducer_backend, reducer_prompt, input, reducer_config, event_tx, per_source, workers)
+            });
+
+            for event in event_rx {
+                stats.record(&event);
+                on_event(event);
+            }
+            join_stage(reducer_handle, "reducer")
+        })?;
+
+        stats.elapsed = started.elapsed();
+        on_event(PipelineEvent::RunStats(stats));
+        Ok(())
+    }
+
+    /// Whether the chunks are summarized by `refine_loop` instead of the map and reduce.
+    fn refines(&self) -> bool {
+        self.reduce && self.config.reduce_strategy == ReduceStrategy::Refine && !self.config.merge.enabled
+    }
+
+    fn stream_inputs<R, F>(&self, inputs: Vec<Input<R>>, mut on_event: F) -> Result<()>
+    where
+        R: Read + Send,
+        F: FnMut(PipelineEvent),
+    {
+        let started = Instant::now();
+        let mut stats = RunStats::default();
+        let (event_tx, event_rx) = unbounded::<PipelineEvent>();
+        let per_source = inputs.len() > 1;
+        let refine = self.refines();
+
+        thread::scope(|s| {
+            let mut worker_handles = Vec::with_capacity(self.workers);
+
+            // Set up the queue for workers and reducer
+            let (worker_tx, worker_rx) = bounded::<ChunkTask>(self.workers * 2);
+            let (reducer_tx, reducer_rx) = bounded::<ChunkResult>(self.workers * 2);
+            let (total_tx, total_rx) = bounded::<usize>(1);
+
+            for id in 0..self.workers {
+                let rx_clone = worker_rx.clone();
+                let tx_clone = reducer_tx.clone();
+                let events = event_tx.clone();
+                let backend_clone = self.backend.clone();
+                let system_prompt = self.system_prompt.clone();
+                let config_clone = self.config.clone();
+                // One refine worker takes every chunk, in order
+                if refine {
+                    worker_handles.push(s.spawn(move || {
+                        refine_loop(backend_clone, rx_clone, system_prompt, config_clone, events, per_source)
+                    }));
+                    break;
+                }
+                worker_handles.push(s.spawn(move || {
+                    worker_loop(id, backend_clone, rx_clone, system_prompt, config_clone, events, tx_clone)
+                }));
+            }
+
+            // Spawn Reducer Thread (map-only and refine runs drop the results instead)
+            let reducer_handle = (self.reduce && !refine).then(|| {
+                let reducer_backend = self.backend.clone();
+                let reducer_prompt = self.system_prompt.clone();
+                let reducer_config = self.config.clone();
+                let reducer_events = event_tx.clone();
+                let workers = self.workers;
+                let input = ReducerInput { results: reducer_rx, total: total_rx };
+                s.spawn(move || {
+                    run_reducer(
+                        reducer_backend,
+                        reducer_prompt,
+                        input,
+                        reducer_config,
+                        reducer_events,
+                        per_source,
+                        workers,
+                    )
+                })
+            });
+
+            // Drop the originals so the channels close once every thread is done
+            drop(worker_rx);
+            drop(reducer_tx);
+
+            // Smart Chunking pipeline
+            let chunk_backend = self.backend.clone();
+            let chunk_options = ChunkOptions {
+                target_tokens: self.chunk_tokens,
+                overlap_tokens: self.config.chunk_overlap_tokens,
+                mode: self.config.chunk_mode,
+                encoding: self.encoding,
+                preprocess: self.config.preprocess,
+            };
+            let chunker_events = event_tx;
+            let chunker_handle = s.spawn(move || {
+                let mut next_index = 0;
+                for input in inputs {
+                    let (source, reader): (_, Box<dyn Read + Send + '_>) = match input {
+                        Input::Reader(reader) => (None, Box::new(reader)),
+                        Input::File(path) => {
+                            let file = File::open(&path)
+                                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+                            (Some(path), Box::new(file))
+                        }
+                    };
+                    let tx = worker_tx.clone();
+                    let report = parse_and_chunk(chunk_backend.as_ref(), reader, source.clone(), next_index, tx, &chunk_options)?;
+                    next_index += report.chunks;
+                    let _ = chunker_events.send(PipelineEvent::InputDecoded {
+                        source,
+                        encoding: report.decode.encoding.name(),
+                        replacements: report.decode.replacements,
+                    });
+                }
+                // Lets the reducer report chunks after the last result it got as missing
+                let _ = total_tx.send(next_index);
+                Ok(())
+            });
+
+            let mut sequencer = Resequencer::default();
+            for event in event_rx {
+                stats.record(&event);
+                if self.ordered {
+                    sequencer.push(event, &mut on_event);
+                } else {
+                    on_event(event);
+                }
+            }
+            // Chunks after one that never arrived (its worker failed)
+            sequencer.flush(&mut on_event);
+
+            // Input errors come first: they usually explain why the workers stopped
+            let mut result = join_stage(chunker_handle, "chunker");
+            for handle in worker_handles {
+                result = result.and(join_stage(handle, "worker"));
+            }
+            match reducer_handle {
+                Some(handle) => result.and(join_stage(handle, "reducer")),
+                None => result,
+            }
+        })?;
+
+        stats.elapsed = started.elapsed();
+        on_event(PipelineEvent::RunStats(stats));
+        Ok(())
+    }
+}
+
+/// Holds back chunk events until every earlier chunk index has been delivered,
+/// the same way `run_reducer` orders the results it reduces.
+#[derive(Default)]
+struct Resequencer {
+    pending: BTreeMap<usize, PipelineEvent>,
+    next_index: usize,
+}
+
+impl Resequencer {
+    fn push(&mut self, event: PipelineEvent, on_event: &mut impl FnMut(PipelineEvent)) {
+        let index = match &event {
+            PipelineEvent::Chunk { index, .. }
+            | PipelineEvent::SilentChunk { index, .. }
+            | PipelineEvent::InvalidChunk { index, .. } => *index,
+            _ => return on_event(event),
+        };
+        self.pending.insert(index, event);
+        while let Some(event) = self.pending.remove(&self.next_index) {
+            on_event(event);
+            self.next_index += 1;
+        }
+    }
+
+    fn flush(&mut self, on_event: &mut impl FnMut(PipelineEvent)) {
+        for (_, event) in std::mem::take(&mut self.pending) {
+            on_event(event);
+        }
+    }
+}
+
+fn join_stage(handle: thread::ScopedJoinHandle<'_, Result<()>>, stage: &'static str) -> Result<()> {
+    handle.join().unwrap_or(Err(Error::ThreadPanicked(stage)))
+}
+
+impl PipelineOutput {
+    fn collect(&mut self, event: PipelineEvent) {
+        match event {
+            PipelineEvent::InputDecoded { encoding, replacements, .. } => {
+                self.input_encoding = Some(encoding);
+                self.replacements += replacements;
+            }
+            PipelineEvent::Chunk { index, text, .. } => self.chunks.push((index, text)),
+            PipelineEvent::SilentChunk { index, .. } => self.silent_chunks.push(index),
+            PipelineEvent::InvalidChunk { index, errors, .. } => self.invalid_chunks.push((index, errors)),
+            PipelineEvent::MetaPrompt { text } => self.meta_prompt = Some(text),
+            PipelineEvent::IntermediateSummary { text, .. } => self.intermediate_summaries.push(text),
+            PipelineEvent::Completeness(report) => self.completeness = Some(report),
+            PipelineEvent::MergedResult { source, data } => self.merged_results.push((source, data)),
+            PipelineEvent::SourceSummary { source, text, .. } => self.source_summaries.push((source, text)),
+            PipelineEvent::FinalSummary { text, .. } => self.final_summary = Some(text),
+            PipelineEvent::RunStats(stats) => self.stats = stats,
+            PipelineEvent::MetaPromptFailed { .. }
+            | PipelineEvent::IntermediateReduceStarted { .. }
+            | PipelineEvent::SourceSummaryDelta { .. }
+            | PipelineEvent::FinalSummaryDelta { .. } => {}
+        }
+    }
+}
+
+impl RunStats {
+    fn record(&mut self, event: &PipelineEvent) {
+        let usage = match event {
+            PipelineEvent::Chunk { usage, .. } => {
+                self.chunks += 1;
+                usage
+            }
+            PipelineEvent::SilentChunk { usage, .. } => {
+                self.chunks += 1;
+                self.silent_chunks += 1;
+                usage
+            }
+            PipelineEvent::InvalidChunk { usage, .. } => {
+                self.chunks += 1;
+                self.invalid_chunks += 1;
+                usage
+            }
+            PipelineEvent::IntermediateSummary { usage, .. } => {
+                self.intermediate_reduces += 1;
+                usage
+            }
+            PipelineEvent::SourceSummary { usage, .. } | PipelineEvent::FinalSummary { usage, .. } => usage,
+            _ => return,
+        };
+        self.input_tokens += usage.input_tokens;
+        self.output_tokens += usage.output_tokens;
+    }
+}
@@ -0,0 +1,449 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Text normalization applied to the decoded input before chunking.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Preprocess {
+    /// Pass the input through unchanged.
+    #[default]
+    None,
+    /// Aozora Bunko texts: drop ruby, `［＃…］` annotations and the header/footer notes.
+    Aozora,
+}
+
+impl Preprocess {
+    pub fn preprocessor(self) -> Option<Box<dyn Preprocessor>> {
+        match self {
+            Preprocess::None => None,
+            Preprocess::Aozora => Some(Box::new(AozoraNormalizer::new())),
+        }
+    }
+}
+
+/// A streaming text filter between the decoder and the chunker.
+pub trait Preprocessor: Send {
+    /// Appends the filtered form of `text` to `output`. Text may be held back
+    /// until a later call (e.g. up to the next newline).
+    fn push(&mut self, text: &str, output: &mut String);
+
+    /// Flushes whatever is still held back at end of input.
+    fn finish(&mut self, output: &mut String);
+}
+
+/// Header lines after which the `------` block of notation notes must have started.
+const HEADER_SCAN_LINES: usize = 20;
+
+/// Characters of text per generation of the flattened-ruby statistics; two
+/// generations are kept, so memory stays bounded on long inputs.
+const STATS_WINDOW_CHARS: usize = 500_000;
+
+/// Upper bounds on reading length when looking for flattened ruby.
+const MAX_READING_PER_KANJI: usize = 3;
+const MAX_READING_CHARS: usize = 8;
+
+/// Particles and auxiliaries that follow kanji all the time; never taken for a reading.
+const PARTICLES: [&str; 20] = [
+    "ぐらい", "くらい", "ばかり", "ながら", "ほど", "など", "まで", "だけ", "より", "から", "しか", "さえ", "でも",
+    "なら", "って", "という", "として", "について", "そう", "じゃ",
+];
+const SINGLE_KANA_PARTICLES: [char; 15] = ['の', 'を', 'に', 'へ', 'と', 'で', 'も', 'や', 'は', 'が', 'か', 'ね', 'よ', 'な', 'だ'];
+
+/// Kana that start particles rather than readings; too ambiguous to strip.
+const PARTICLE_INITIALS: [char; 9] = ['の', 'を', 'に', 'へ', 'と', 'で', 'も', 'は', 'が'];
+
+/// Small kana only continue a word, so neither a reading nor okurigana
+/// starts with one ("思わなかった" is not "思" + "わなか" + "った").
+const SMALL_KANA: [char; 9] = ['っ', 'ゃ', 'ゅ', 'ょ', 'ぁ', 'ぃ', 'ぅ', 'ぇ', 'ぉ'];
+
+/// Normalizes Aozora Bunko text, line by line.
+///
+/// - `｜漢字《かな》` and `漢字《かな》` ruby keep only the base text; a `｜`
+///   not followed by ruby is text.
+/// - `［＃…］` annotations are dropped; a `※［＃…］` gaiji note becomes the
+///   character it describes (see `gaiji`).
+/// - The `------` notation notes under the title and everything from the
+///   `底本：` colophon on are dropped.
+/// - Ruby flattened into plain text by copying from HTML ("親譲おやゆずりの")
+///   is removed where the text itself gives it away: a hiragana prefix after
+///   a kanji run is a reading if the same kanji run also appears directly
+///   followed by what comes after that prefix ("腰こしを" next to "腰を"),
+///   never by its first kana, and the prefix is neither a particle nor seen
+///   after other kanji. Once found, a reading is also removed from later
+///   occurrences. Skipped for texts that use proper `《》` ruby. Only the
+///   last `STATS_WINDOW_CHARS` to twice that many characters count.
+pub struct AozoraNormalizer {
+    partial: String,
+    lines_seen: usize,
+    header: HeaderState,
+    in_footer: bool,
+    explicit_ruby: bool,
+    /// Statistics of the current window and of the one before.
+    stats: RubyStats,
+    previous: RubyStats,
+}
+
+/// What the flattened-ruby detection has seen in one window of text.
+#[derive(Default)]
+struct RubyStats {
+    chars: usize,
+    /// Occurrences of "kanji run followed by one or two chars".
+    continuations: HashMap<(String, String), usize>,
+    /// Readings detected for kanji runs.
+    readings: HashMap<String, String>,
+    /// Hiragana sequences seen right after a kanji run, with that run (empty
+    /// once seen after more than one).
+    hiragana_after: HashMap<String, String>,
+}
+
+#[derive(PartialEq)]
+enum HeaderState {
+    Scanning,
+    InNotes,
+    Done,
+}
+
+impl Preprocessor for AozoraNormalizer {
+    fn push(&mut self, text: &str, output: &mut String) {
+        self.partial.push_str(text);
+        let Some(last_newline) = self.partial.rfind('\n') else {
+            return;
+        };
+        let rest = self.partial.split_off(last_newline + 1);
+        let complete = std::mem::replace(&mut self.partial, rest);
+        for line in complete.split_inclusive('\n') {
+            self.push_line(line, output);
+        }
+    }
+
+    fn finish(&mut self, output: &mut String) {
+        let line = std::mem::take(&mut self.partial);
+        if !line.is_empty() {
+            self.push_line(&line, output);
+        }
+    }
+}
+
+impl AozoraNormalizer {
+    pub fn new() -> Self {
+        Self {
+            partial: String::new(),
+            lines_seen: 0,
+            header: HeaderState::Scanning,
+            in_footer: false,
+            explicit_ruby: false,
+            stats: RubyStats::default(),
+            previous: RubyStats::default(),
+        }
+    }
+
+    fn push_line(&mut self, line: &str, output: &mut String) {
+        self.lines_seen += 1;
+        let content = line.trim_end_matches(['\r', '\n']);
+
+        let is_rule = content.chars().count() >= 10 && content.chars().all(|c| c == '-');
+        match self.header {
+            HeaderState::Scanning if is_rule => {
+                self.header = HeaderState::InNotes;
+                return;
+            }
+            HeaderState::Scanning if self.lines_seen > HEADER_SCAN_LINES => self.header = HeaderState::Done,
+            HeaderState::InNotes => {
+                if is_rule {
+                    self.header = HeaderState::Done;
+                }
+                return;
+            }
+            _ => {}
+        }
+
+        if content.starts_with("底本：") {
+            self.in_footer = true;
+        }
+        if self.in_footer {
+            return;
+        }
+
+        if content.contains('《') {
+            self.explicit_ruby = true;
+        }
+        let stripped = strip_markup(content);
+        let normalized = if self.explicit_ruby { stripped } else { self.strip_flattened_readings(&stripped) };
+        output.push_str(&normalized);
+        output.push_str(&line[content.len()..]);
+    }
+
+    fn strip_flattened_readings(&mut self, line: &str) -> String {
+        let chars: Vec<char> = line.chars().collect();
+        if self.stats.chars >= STATS_WINDOW_CHARS {
+            self.previous = std::mem::take(&mut self.stats);
+        }
+        self.stats.chars += chars.len();
+
+        // (kanji start, kanji end = hiragana start, hiragana end)
+        let mut runs = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            if !is_kanji(chars[i]) {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i < chars.len() && is_kanji(chars[i]) {
+                i += 1;
+            }
+            let kanji_end = i;
+            while i < chars.len() && is_hiragana(chars[i]) {
+                i += 1;
+            }
+            runs.push((start, kanji_end, i));
+        }
+
+        // The whole line counts as context, so a later plain occurrence in
+        // the same paragraph already helps
+        for &(start, kanji_end, hira_end) in &runs {
+            let kanji: String = chars[start..kanji_end].iter().collect();
+            let hira_end = hira_end.min(kanji_end + MAX_READING_CHARS);
+            for end in kanji_end + 1..=hira_end {
+                let hiragana: String = chars[kanji_end..end].iter().collect();
+                let after = self.stats.hiragana_after.entry(hiragana).or_insert_with(|| kanji.clone());
+                if *after != kanji {
+                    after.clear();
+                }
+            }
+            for len in 1..=2 {
+                if let Some(next) = chars.get(kanji_end..kanji_end + len) {
+                    *self.stats.continuations.entry((kanji.clone(), next.iter().collect())).or_default() += 1;
+                }
+            }
+        }
+
+        let mut output = String::with_capacity(line.len());
+        let mut copied = 0;
+        for (start, kanji_end, hira_end) in runs {
+            let kanji: String = chars[start..kanji_end].iter().collect();
+            let hiragana = &chars[kanji_end..hira_end];
+            let known = self.stats.readings.get(&kanji).or_else(|| self.previous.readings.get(&kanji));
+            let reading_len = match known {
+                Some(reading) if hiragana.iter().copied().take(reading.chars().count()).eq(reading.chars()) => {
+                    reading.chars().count()
+                }
+                _ => match self.detect_reading(&kanji, &chars[kanji_end..], hiragana.len()) {
+                    Some(len) => {
+                        self.stats.readings.insert(kanji, hiragana[..len].iter().collect());
+                        len
+                    }
+                    None => 0,
+                },
+            };
+            output.extend(&chars[copied..kanji_end]);
+            copied = kanji_end + reading_len;
+        }
+        output.extend(&chars[copied..]);
+        output
+    }
+
+    /// Length of the reading at the start of `rest` (the text after `kanji`,
+    /// starting with `hiragana_len` hiragana), if the rest of the text shows
+    /// `kanji` directly followed by the two chars that come after it.
+    fn detect_reading(&self, kanji: &str, rest: &[char], hiragana_len: usize) -> Option<usize> {
+        let hiragana = &rest[..hiragana_len];
+        let first = *hiragana.first()?;
+        let seen = |next: &[char]| {
+            let key = (kanji.to_string(), next.iter().collect());
+            [&self.stats, &self.previous]
+                .iter()
+                .map(|stats| stats.continuations.get(&key).copied().unwrap_or(0))
+                .sum::<usize>()
+        };
+        // The first kana is a plain continuation elsewhere, so it is not a reading
+        if seen(&[first]) > 1 {
+            return None;
+        }
+
+        let kanji_len = kanji.chars().count();
+        let max_len = (kanji_len * MAX_READING_PER_KANJI).min(MAX_READING_CHARS).min(hiragana_len);
+        let len = (kanji_len..=max_len).find(|&len| {
+            rest.get(len..len + 2)
+                .is_some_and(|next| next[0] != first && seen(next) > 0)
+        })?;
+
+        let reading: String = hiragana[..len].iter().collect();
+        let is_particle = PARTICLES.iter().any(|p| reading.starts_with(p))
+            || PARTICLE_INITIALS.contains(&first)
+            || SMALL_KANA.contains(&first)
+            || first == 'ん'
+            || reading.ends_with('っ')
+            || (len == 1 && SINGLE_KANA_PARTICLES.contains(&first))
+            || hiragana.get(len).is_some_and(|c| SMALL_KANA.contains(c));
+        let after_other_kanji = [&self.stats, &self.previous]
+            .iter()
+            .any(|stats| stats.hiragana_after.get(&reading).is_some_and(|k| k != kanji));
+        (!is_particle && !after_other_kanji).then_some(len)
+    }
+}
+
+impl Default for AozoraNormalizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Drops ruby markup and `［＃…］` annotations from one line.
+fn strip_markup(line: &str) -> String {
+    let mut output = String::with_capacity(line.len());
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            // Marks where the base text of the ruby that follows starts; a
+            // literal `｜` is kept
+            '｜' if starts_ruby(chars.clone()) => {}
+            '《' => {
+                for c in chars.by_ref() {
+                    if c == '》' {
+                        break;
+                    }
+                }
+            }
+            '※' if chars.peek() == Some(&'［') => {
+                let note: String = chars.by_ref().skip(2).take_while(|&c| c != '］').collect();
+                output.push_str(&gaiji(&note));
+            }
+            '［' if chars.peek() == Some(&'＃') => {
+                for c in chars.by_ref() {
+                    if c == '］' {
+                        break;
+                    }
+                }
+            }
+            _ => output.push(c),
+        }
+    }
+    output
+}
+
+/// The character a `※［＃…］` gaiji note describes: itself if the note gives
+/// its code point (`U+6F2A`), else the description (`「木＋吉」`) in `〔〕`.
+fn gaiji(note: &str) -> String {
+    let code_point = note
+        .split('、')
+        .find_map(|field| field.strip_prefix("U+"))
+        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+        .and_then(char::from_u32);
+    if let Some(c) = code_point {
+        return c.to_string();
+    }
+    let field = note.split('、').next().unwrap_or_default();
+    let description = field.strip_prefix('「').and_then(|f| f.strip_suffix('」')).unwrap_or(field);
+    format!("〔{}〕", description)
+}
+
+/// Whether the text after a `｜` is base text followed by `《…》` ruby.
+fn starts_ruby(mut rest: impl Iterator<Item = char>) -> bool {
+    let mut base = 0;
+    for c in rest.by_ref() {
+        match c {
+            '《' => return base > 0,
+            '｜' | '》' | '\n' => return false,
+            _ => base += 1,
+        }
+    }
+    false
+}
+
+fn is_kanji(c: char) -> bool {
+    matches!(c, '\u{4E00}'..='\u{9FFF}' | '\u{3400}'..='\u{4DBF}' | '\u{F900}'..='\u{FAFF}' | '々' | '〆')
+}
+
+fn is_hiragana(c: char) -> bool {
+    matches!(c, '\u{3041}'..='\u{3096}' | 'ゝ' | 'ゞ')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(text: &str) -> String {
+        let mut normalizer = AozoraNormalizer::new();
+        let mut output = String::new();
+        normalizer.push(text, &mut output);
+        normalizer.finish(&mut output);
+        output
+    }
+
+    #[test]
+    fn ruby_keeps_the_base_text() {
+        assert_eq!(normalize("吾輩《わがはい》は｜猫である《ねこである》。\n"), "吾輩は猫である。\n");
+    }
+
+    #[test]
+    fn literal_bar_without_ruby_is_kept() {
+        assert_eq!(normalize("甲｜乙の表。\n"), "甲｜乙の表。\n");
+        assert_eq!(normalize("A｜B｜C《しー》\n"), "A｜BC\n");
+        assert_eq!(normalize("｜《》\n"), "｜\n");
+    }
+
+    #[test]
+    fn gaiji_notes_keep_the_character() {
+        assert_eq!(normalize("※［＃「さんずい＋倚」、U+6F2A、325-8］いた。\n"), "漪いた。\n");
+        assert_eq!(normalize("※［＃「木＋吉」、第3水準1-85-69］の木。\n"), "〔木＋吉〕の木。\n");
+        assert_eq!(normalize("時※［＃二の字点、1-2-22］\n"), "時〔二の字点〕\n");
+        assert_eq!(normalize("本文［＃「本文」に傍点］。\n"), "本文。\n");
+    }
+
+    #[test]
+    fn flattened_reading_next_to_a_plain_occurrence_is_removed() {
+        assert_eq!(normalize("彼は腰を下ろす。\n腰こしを下ろした。\n"), "彼は腰を下ろす。\n腰を下ろした。\n");
+        // The same line is context too, and a found reading applies later on
+        assert_eq!(
+            normalize("腰こしを下ろし、また腰を下ろした。\n腰こしが痛い。\n"),
+            "腰を下ろし、また腰を下ろした。\n腰が痛い。\n"
+        );
+    }
+
+    #[test]
+    fn okurigana_is_not_taken_for_a_reading() {
+        // "わなか" before "った" (seen after 思) ends next to a small kana
+        let text = "そう思った。\nそうは思わなかった。\n";
+        assert_eq!(normalize(text), text);
+        let text = "彼は笑った。\n彼は笑わなかった。\n";
+        assert_eq!(normalize(text), text);
+    }
+
+    #[test]
+    fn particles_and_n_are_not_taken_for_a_reading() {
+        let text = "山は遠い。\n山までは遠い。\n";
+        assert_eq!(normalize(text), text);
+        let text = "何だ。\n何んだ。\n";
+        assert_eq!(normalize(text), text);
+        let text = "酒が好きだ。\n酒のみが好きだ。\n";
+        assert_eq!(normalize(text), text);
+    }
+
+    #[test]
+    fn hiragana_seen_after_other_kanji_is_not_a_reading() {
+        let text = "木かげで休む。\n林かげで休む。\n木で休む。\n";
+        assert_eq!(normalize(text), text);
+    }
+
+    #[test]
+    fn explicit_ruby_disables_the_heuristic() {
+        let text = "吾輩《わがはい》は腰を下ろす。\n腰こしを下ろした。\n";
+        assert_eq!(normalize(text), "吾輩は腰を下ろす。\n腰こしを下ろした。\n");
+    }
+
+    #[test]
+    fn statistics_are_limited_to_two_windows() {
+        let mut normalizer = AozoraNormalizer::new();
+        let mut output = String::new();
+        normalizer.push("彼は腰を下ろす。\n", &mut output);
+        let line = format!("{}\n", "山".repeat(STATS_WINDOW_CHARS));
+        normalizer.push(&line, &mut output);
+        normalizer.push(&line, &mut output);
+        normalizer.push("腰こしを下ろした。\n", &mut output);
+        // The plain occurrence rotated out, so nothing is removed
+        assert!(output.ends_with("腰こしを下ろした。\n"));
+        assert!(normalizer.stats.continuations.len() + normalizer.previous.continuations.len() <= 4);
+    }
+}
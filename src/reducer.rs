@@ -1,224 +1,714 @@
 use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
 use std::sync::Arc;
-use crossbeam_channel::{Receiver, bounded};
-use llama_cpp_2::llama_backend::LlamaBackend;
-use llama_cpp_2::model::LlamaModel;
-use llama_cpp_2::context::params::LlamaContextParams;
-use llama_cpp_2::llama_batch::LlamaBatch;
-use llama_cpp_2::sampling::LlamaSampler;
-use llama_cpp_2::token::data_array::LlamaTokenDataArray;
-use std::num::NonZeroU32;
-use std::thread;
-use std::io::{self, Write};
+use crossbeam_channel::{Receiver, Sender, bounded, unbounded};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
 use crate::prompts::generate_meta_prompt;
+use crate::backend::{InferenceBackend, InferenceSession};
+use crate::chunker::longest_fitting_prefix;
 use crate::config::*;
+use crate::error::{Error, Result};
+use crate::generator::GenerationParams;
+use crate::merge::Merger;
+use crate::pipeline::{CompletenessReport, PipelineEvent};
+use crate::schema::Schema;
+use crate::types::{ChunkResult, ChunkStatus, Usage};
 
+/// Text waiting to be summarized, with its token count.
+#[derive(Default)]
+struct Rolling {
+    buffer: String,
+    tokens: usize,
+}
+
+/// Releases the chunk outputs in index order, counting how every chunk
+/// ended. Silent and failed chunks only advance the order.
+#[derive(Default)]
+struct InOrder {
+    pending: BTreeMap<usize, ChunkResult>,
+    next_index: usize,
+    /// Set once the channel is closed: chunks still missing will not come.
+    closed: bool,
+    /// Number of chunks in the run, if the input was read to the end.
+    total: Option<usize>,
+    report: CompletenessReport,
+}
+
+/// What the reducer reads: the map results, and the number of chunks once
+/// the chunker is done with the input.
+pub struct ReducerInput {
+    pub results: Receiver<ChunkResult>,
+    /// Closed without a count when the input could not be read to the end
+    /// (or for `stream_results`, where only the results are known).
+    pub total: Receiver<usize>,
+}
+
+impl InOrder {
+    fn push(&mut self, result: ChunkResult) {
+        self.pending.insert(result.index, result);
+    }
+
+    /// Receives the next result, or marks the results as complete. Returns
+    /// false once `next_output` has had its chance to flush what is left.
+    fn recv(&mut self, input: &ReducerInput) -> bool {
+        match input.results.recv() {
+            Ok(result) => self.push(result),
+            Err(_) if self.closed => return false,
+            Err(_) => {
+                self.closed = true;
+                self.total = input.total.recv().ok();
+            }
+        }
+        true
+    }
+
+    /// The next output in index order, if it is available yet.
+    fn next_output(&mut self) -> Option<(usize, Option<Arc<Path>>, String)> {
+        loop {
+            let result = match self.pending.remove(&self.next_index) {
+                Some(result) => result,
+                // Skip the chunks that never arrived (e.g. their worker panicked)
+                None if self.closed => {
+                    // Up to the end of the run once nothing else is pending
+                    let index = match self.pending.keys().next() {
+                        Some(&index) => index,
+                        None => self.total.take().filter(|&total| total > self.next_index)?,
+                    };
+                    self.report.missing.extend(self.next_index..index);
+                    self.next_index = index;
+                    continue;
+                }
+                None => return None,
+            };
+            self.next_index += 1;
+            match result.status {
+                ChunkStatus::Output(text) => {
+                    self.report.reduced += 1;
+                    return Some((result.index, result.source, text));
+                }
+                ChunkStatus::Silent => self.report.silent += 1,
+                ChunkStatus::Failed => self.report.failed.push(result.index),
+            }
+        }
+    }
+
+    /// Counts an output returned by `next_output` as failed after all.
+    fn reject(&mut self, index: usize) {
+        self.report.reduced -= 1;
+        self.report.failed.push(index);
+    }
+}
+
+/// Runs intermediate reduces; every tree reduce thread gets its own copy.
+#[derive(Clone)]
+struct Compressor {
+    backend: Arc<dyn InferenceBackend>,
+    config: Arc<AppConfig>,
+    events: Sender<PipelineEvent>,
+    params: GenerationParams,
+}
+
+/// A group for the tree reduce pool: intermediate reduce index, system prompt and text.
+type Job = (usize, String, String);
+/// An intermediate summary from the pool, with its index.
+type Done = Result<(usize, String)>;
+
+/// The tree strategy: groups of `fan_in` results of one source are reduced
+/// in a pool of extra contexts (the first level while the map is still
+/// running), level by level until at most `fan_in` summaries remain that
+/// fit the threshold together.
+struct Tree {
+    fan_in: usize,
+    workers: usize,
+    /// Contexts the pool may open: one while the map workers still hold
+    /// theirs, `workers` once the map is done.
+    contexts: usize,
+    /// Results of the current source that are not part of a group yet.
+    entries: Vec<String>,
+    tokens: usize,
+    /// Groups handed to the pool whose summaries were not collected yet.
+    in_flight: usize,
+    /// Started with the first group, joined once the source is reduced.
+    pool: Option<Pool>,
+}
+
+/// Threads that reduce groups, each on its own context (created with its
+/// first group). Dropping the pool waits for them, also when the reduce failed.
+struct Pool {
+    compressor: Compressor,
+    jobs: Option<Sender<Job>>,
+    job_rx: Receiver<Job>,
+    done_tx: Sender<Done>,
+    done: Receiver<Done>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+/// State shared by every summary the reducer writes: the meta prompt and
+/// the intermediate reduce counter.
+struct Reducer {
+    backend: Arc<dyn InferenceBackend>,
+    config: Arc<AppConfig>,
+    events: Sender<PipelineEvent>,
+    params: GenerationParams,
+    compressor: Compressor,
+    /// Buffered tokens that trigger an intermediate reduce.
+    threshold: usize,
+    reducer_prompt: String,
+    sample_summaries: String,
+    meta_prompt_rx: Option<Receiver<Result<String>>>,
+    dynamic_prompt: Option<String>,
+    /// Outputs seen so far, the first of which seed the meta prompt.
+    sampled: usize,
+    intermediate_count: usize,
+}
+
+/// Reduces the map results into a final summary. With `per_source`, every
+/// input file gets its own summary and, if `combined_summary` is set, a
+/// cross-file summary is written from those at the end. The tree strategy
+/// reduces each level on up to `workers` contexts at once.
 pub fn run_reducer(
-    reducer_model: Arc<LlamaModel>,
-    reducer_backend: Arc<LlamaBackend>,
+    reducer_backend: Arc<dyn InferenceBackend>,
     reducer_prompt: String,
-    reducer_rx: Receiver<(usize, String)>,
+    input: ReducerInput,
     config: Arc<AppConfig>,
-) {
-    let mut ordered_chunks = BTreeMap::new();
-    let mut next_expected_idx = 0;
-    
-    let mut rolling_buffer = String::new();
-    let mut rolling_token_count = 0;
-    let mut intermediate_count = 0;
-    
-    let mut sample_summaries = String::new();
-    let mut meta_prompt_rx: Option<Receiver<String>> = None;
-    let mut dynamic_prompt: Option<String> = None;
-    let meta_model = reducer_model.clone();
-    let meta_backend = reducer_backend.clone();
-    
+    events: Sender<PipelineEvent>,
+    per_source: bool,
+    workers: usize,
+) -> Result<()> {
+    if config.merge.enabled {
+        return run_merge(reducer_backend, reducer_prompt, input, config, events, per_source);
+    }
+
+    let mut in_order = InOrder::default();
+
+    let mut rolling = Rolling::default();
+    let threshold = reduce_threshold(reducer_backend.as_ref(), &config, &reducer_prompt)?;
+    let mut tree = Tree::new(config.reduce_fan_in, workers);
+    let mut current_source: Option<Arc<Path>> = None;
+    let mut combined = Rolling::default();
+
     // Context configuration for reducing
-    let ctx_params = LlamaContextParams::default()
-        .with_n_ctx(Some(NonZeroU32::new(config.main_ctx_size).unwrap()))
-        .with_n_batch(config.batch_size_limit as u32)
-        .with_n_ubatch(config.batch_size_limit as u32);
-    let mut reducer_ctx = reducer_model
-        .new_context(reducer_backend.as_ref(), ctx_params)
-        .expect("Failed to create reducer context");
-
-    loop {
-        // Process all pending chunks
-        match reducer_rx.recv() {
-            Ok((idx, text)) => {
-                ordered_chunks.insert(idx, text);
-            }
-            Err(_) => {
-                // Channel closed, process remaining and break
-                break;
-            }
+    let mut session = reducer_backend.new_session(config.main_ctx_size)?;
+    // Summaries stay within the room `check_fits` keeps free, and follow the
+    // same schema as the chunk results
+    let mut params = GenerationParams::from_config(&config).with_max_new_tokens(config.reduce_output_tokens);
+    if let Some(schema) = config.schema.clone().map(Schema::new).transpose()? {
+        params = params.with_grammar(schema.grammar());
+    }
+    let compressor = Compressor {
+        backend: reducer_backend.clone(),
+        config: config.clone(),
+        events: events.clone(),
+        params: params.clone(),
+    };
+    let mut reducer = Reducer {
+        backend: reducer_backend.clone(),
+        params,
+        config: config.clone(),
+        events,
+        compressor,
+        threshold,
+        reducer_prompt,
+        sample_summaries: String::new(),
+        meta_prompt_rx: None,
+        dynamic_prompt: None,
+        sampled: 0,
+        intermediate_count: 0,
+    };
+
+    // Process all pending chunks until the channel closes, then whatever a failed worker held up
+    while in_order.recv(&input) {
+        if in_order.closed {
+            tree.map_done();
         }
 
         // Continuously append chunks in order
-        while let Some(text) = ordered_chunks.remove(&next_expected_idx) {
-            let chunk_tokens = reducer_model.str_to_token(&text, llama_cpp_2::model::AddBos::Never).unwrap_or_default().len();
-            rolling_buffer.push_str(&format!("[Data {}]\n{}\n\n", next_expected_idx, text));
-            rolling_token_count += chunk_tokens;
-            
-            if next_expected_idx < 3 {
-                sample_summaries.push_str(&text);
-                sample_summaries.push_str("\n\n");
+        while let Some((index, source, text)) = in_order.next_output() {
+            if per_source && source != current_source && !(rolling.buffer.is_empty() && tree.is_empty()) {
+                let source = current_source.take();
+                let rolling = match tree.is_empty() {
+                    true => std::mem::take(&mut rolling),
+                    false => tree.finish(&mut reducer)?,
+                };
+                reducer.finish_source(session.as_mut(), rolling, source, &mut combined)?;
             }
-            
-            // Spawning the meta-prompt evaluation asynchronously when it has processed 2 chunks
-            if next_expected_idx == 1 && meta_prompt_rx.is_none() {
-                let (tx, rx) = bounded(1);
-                meta_prompt_rx = Some(rx);
-                let m_model = meta_model.clone();
-                let m_backend = meta_backend.clone();
-                let sample = sample_summaries.clone();
-                let m_config = config.clone();
-                thread::spawn(move || {
-                    let prompt = generate_meta_prompt(m_model, m_backend, sample, m_config);
-                    let _ = tx.send(prompt);
-                });
+            current_source = source;
+
+            reducer.sample(&text);
+            let entry = format!("[Data {}]\n{}\n\n", index, text);
+            match config.reduce_strategy {
+                // Refine runs never reach the reducer
+                ReduceStrategy::Rolling | ReduceStrategy::Refine => reducer.append(session.as_mut(), &mut rolling, &entry)?,
+                ReduceStrategy::Tree => tree.push(&mut reducer, session.as_mut(), &entry)?,
             }
-            
-            next_expected_idx += 1;
-            
-            // Intermediate Reduce if buffer exceeds 24,000 tokens
-            if rolling_token_count >= 24000 {
-                println!("\n[Intermediate Reduce {} Triggered]", intermediate_count);
-                
-                if dynamic_prompt.is_none() {
-                    if let Some(rx) = &meta_prompt_rx {
-                        dynamic_prompt = Some(rx.recv().unwrap_or_else(|_| reducer_prompt.clone()));
-                    } else {
-                        dynamic_prompt = Some(generate_meta_prompt(meta_model.clone(), meta_backend.clone(), sample_summaries.clone(), config.clone()));
-                    }
-                    println!("\n[Meta-Prompt Applied]: {}", dynamic_prompt.as_ref().unwrap());
+        }
+    }
+    let _ = reducer.events.send(PipelineEvent::Completeness(in_order.report));
+
+    // Final Output
+    let rolling = match tree.is_empty() {
+        true => rolling,
+        false => tree.finish(&mut reducer)?,
+    };
+    if per_source {
+        if !rolling.buffer.is_empty() {
+            reducer.finish_source(session.as_mut(), rolling, current_source, &mut combined)?;
+        }
+        if config.combined_summary && !combined.buffer.is_empty() {
+            reducer.finish_run(session.as_mut(), combined, &config.cross_file_reduce_prompt, "cross-file reduce")?;
+        }
+    } else if !rolling.buffer.is_empty() {
+        reducer.finish_run(session.as_mut(), rolling, &config.final_reduce_prompt, "final reduce")?;
+    }
+
+    Ok(())
+}
+
+impl Reducer {
+    /// Collects the first chunk results and starts the meta prompt from them.
+    fn sample(&mut self, text: &str) {
+        if self.sampled < 3 {
+            self.sample_summaries.push_str(text);
+            self.sample_summaries.push_str("\n\n");
+        }
+        self.sampled += 1;
+
+        // Spawning the meta-prompt evaluation asynchronously when it has processed 2 chunks
+        if self.sampled == 2 && self.meta_prompt_rx.is_none() {
+            let (tx, rx) = bounded(1);
+            self.meta_prompt_rx = Some(rx);
+            let m_backend = self.backend.clone();
+            let sample = self.sample_summaries.clone();
+            let m_config = self.config.clone();
+            thread::spawn(move || {
+                let _ = tx.send(generate_meta_prompt(m_backend, sample, m_config));
+            });
+        }
+    }
+
+    /// The system prompt for reduce steps: the meta prompt once it is known.
+    fn prompt(&mut self) -> String {
+        if self.dynamic_prompt.is_none() {
+            let generated = match &self.meta_prompt_rx {
+                Some(rx) => rx.recv().unwrap_or(Err(Error::ThreadPanicked("meta prompt"))),
+                None => generate_meta_prompt(self.backend.clone(), self.sample_summaries.clone(), self.config.clone()),
+            };
+            // Fall back to the user's system prompt, but say so
+            let prompt = match generated {
+                Ok(prompt) => {
+                    let _ = self.events.send(PipelineEvent::MetaPrompt { text: prompt.clone() });
+                    prompt
                 }
-                
-                let intermediate_prompt = config.intermediate_reduce_prompt
-                    .replace("{SYS_PROMPT}", dynamic_prompt.as_ref().unwrap())
-                    .replace("{TEXT}", &rolling_buffer);
-                
-                // Execute Reducer Context
-                reducer_ctx.clear_kv_cache();
-                let tokens = reducer_model.str_to_token(&intermediate_prompt, llama_cpp_2::model::AddBos::Always).unwrap();
-                let mut n_eval = 0;
-                let mut last_batch_tokens = 0;
-                while n_eval < tokens.len() {
-                    let chunk_size = std::cmp::min(tokens.len() - n_eval, config.batch_size_limit);
-                    let mut batch = LlamaBatch::new(chunk_size, 1);
-                    for i in 0..chunk_size {
-                        let token = tokens[n_eval + i].clone();
-                        let is_last = (n_eval + i) == (tokens.len() - 1);
-                        batch.add(token, (n_eval + i) as i32, &[0], is_last).unwrap();
-                    }
-                    reducer_ctx.decode(&mut batch).unwrap();
-                    last_batch_tokens = chunk_size;
-                    n_eval += chunk_size;
+                Err(error) => {
+                    let _ = self.events.send(PipelineEvent::MetaPromptFailed { error: error.to_string() });
+                    self.reducer_prompt.clone()
                 }
+            };
+            self.dynamic_prompt = Some(prompt);
+        }
+        self.dynamic_prompt.clone().unwrap()
+    }
+
+    /// Adds `entry` to `rolling`, compressing the buffer first if the entry
+    /// would take it past the threshold.
+    fn append(&mut self, session: &mut dyn InferenceSession, rolling: &mut Rolling, entry: &str) -> Result<()> {
+        let (entry, tokens) = self.shorten(session, entry)?;
+
+        // Intermediate Reduce if the buffer would no longer fit into a reduce prompt
+        if !rolling.buffer.is_empty() && rolling.tokens + tokens > self.threshold {
+            let index = self.intermediate_count;
+            let sys_prompt = self.prompt();
+
+            // Reset buffer with compressed memory
+            rolling.buffer = self.compressor.run(session, "intermediate reduce", index, &sys_prompt, &rolling.buffer, rolling.tokens)?;
+            rolling.tokens = self.backend.count_tokens(&rolling.buffer)?;
+            self.intermediate_count += 1;
+        }
+        rolling.buffer.push_str(&entry);
+        rolling.tokens += tokens;
+        Ok(())
+    }
+
+    /// Brings an entry that is over the threshold on its own down to it, so
+    /// it cannot push a reduce prompt past the context: it is reduced alone
+    /// (cut to what the intermediate reduce prompt can hold first), and what
+    /// is still over the threshold after that is cut off.
+    fn shorten(&mut self, session: &mut dyn InferenceSession, entry: &str) -> Result<(String, usize)> {
+        let tokens = self.backend.count_tokens(entry)?;
+        if tokens <= self.threshold {
+            return Ok((entry.to_string(), tokens));
+        }
+        let sys_prompt = self.prompt();
+        let template = self.config.intermediate_reduce_prompt
+            .replace("{SYS_PROMPT}", &sys_prompt)
+            .replace("{TEXT}", "");
+        let room = self.config.context_tokens()
+            .saturating_sub(self.config.reduce_output_tokens)
+            .saturating_sub(self.backend.count_tokens(&template)?);
+        let (end, _) = longest_fitting_prefix(self.backend.as_ref(), entry, room)?;
 
-                let mut batch = LlamaBatch::new(1, 1);
-                let mut n_cur = tokens.len() as i32;
-                let mut decoder = encoding_rs::UTF_8.new_decoder();
-                let mut compressed_text = String::new();
-                
-                let mut sampler = LlamaSampler::chain_simple([
-                    LlamaSampler::temp(config.sample_temp),
-                    LlamaSampler::top_k(config.sample_top_k),
-                    LlamaSampler::top_p(config.sample_top_p, 1),
-                    LlamaSampler::penalties(config.penalty_last_n, config.penalty_repeat, 0.05, 0.05),
-                    LlamaSampler::dist(1234),
-                ]);
-                // Isolate history: only penalize newly generated tokens, not the input prompt.
-                // sampler.accept_many(&tokens);
-
-                loop {
-                    let mut candidates_p = LlamaTokenDataArray::from_iter(reducer_ctx.candidates_ith(last_batch_tokens as i32 - 1), false);
-                    last_batch_tokens = 1;
-                    
-                    candidates_p.apply_sampler(&mut sampler);
-                    let new_token_id = candidates_p.selected_token().expect("Failed to sample token");
-                    sampler.accept(new_token_id);
-                    
-                    if new_token_id == reducer_model.token_eos() || n_cur >= config.max_generate_tokens { break; }
-                    let token_str = crate::types::decode_token(&reducer_model, new_token_id, &mut decoder);
-                    compressed_text.push_str(&token_str);
-
-                    batch.clear();
-                    batch.add(new_token_id, n_cur, &[0], true).unwrap();
-                    if reducer_ctx.decode(&mut batch).is_err() { break; }
-                    n_cur += 1;
+        let index = self.intermediate_count;
+        let compressed = self.compressor.run(session, "intermediate reduce", index, &sys_prompt, &entry[..end], tokens)?;
+        self.intermediate_count += 1;
+        let (end, tokens) = longest_fitting_prefix(self.backend.as_ref(), &compressed, self.threshold)?;
+        Ok((compressed[..end].to_string(), tokens))
+    }
+
+    fn summarize(
+        &mut self,
+        session: &mut dyn InferenceSession,
+        rolling: Rolling,
+        template: &str,
+        stage: &str,
+        sink: &mut dyn FnMut(&str),
+    ) -> Result<(String, Usage)> {
+        let prompt = template
+            .replace("{SYS_PROMPT}", &self.prompt())
+            .replace("{TEXT}", &rolling.buffer);
+        check_fits(self.backend.as_ref(), &self.config, stage, &prompt)?;
+        let started = Instant::now();
+        let summary = session.generate(&prompt, &self.params, sink)?;
+        let usage = Usage {
+            input_tokens: rolling.tokens,
+            output_tokens: self.backend.count_tokens(&summary)?,
+            elapsed: started.elapsed(),
+        };
+        Ok((summary, usage))
+    }
+
+    /// Writes the summary of one input file and queues it for the cross-file summary.
+    fn finish_source(
+        &mut self,
+        session: &mut dyn InferenceSession,
+        rolling: Rolling,
+        source: Option<Arc<Path>>,
+        combined: &mut Rolling,
+    ) -> Result<()> {
+        let template = self.config.final_reduce_prompt.clone();
+        let events = self.events.clone();
+        let (summary, usage) = self.summarize(session, rolling, &template, "source summary", &mut |piece| {
+            let _ = events.send(PipelineEvent::SourceSummaryDelta { source: source.clone(), text: piece.to_string() });
+        })?;
+        let _ = self.events.send(PipelineEvent::SourceSummary { source: source.clone(), usage, text: summary.clone() });
+
+        if self.config.combined_summary {
+            let label = source.as_deref().map_or("<stdin>".into(), Path::to_string_lossy);
+            let entry = format!("[Source: {}]\n{}\n\n", label, summary.trim());
+            self.append(session, combined, &entry)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the summary of the whole run.
+    fn finish_run(&mut self, session: &mut dyn InferenceSession, rolling: Rolling, template: &str, stage: &str) -> Result<()> {
+        let events = self.events.clone();
+        let (final_summary, usage) = self.summarize(session, rolling, template, stage, &mut |piece| {
+            let _ = events.send(PipelineEvent::FinalSummaryDelta { text: piece.to_string() });
+        })?;
+        let _ = self.events.send(PipelineEvent::FinalSummary { usage, text: final_summary });
+        Ok(())
+    }
+}
+
+impl Compressor {
+    /// Runs intermediate reduce `index` over `text` and returns the summary as
+    /// a buffer entry; `stage` names it in errors.
+    fn run(
+        &self,
+        session: &mut dyn InferenceSession,
+        stage: &str,
+        index: usize,
+        sys_prompt: &str,
+        text: &str,
+        tokens: usize,
+    ) -> Result<String> {
+        let intermediate_prompt = self.config.intermediate_reduce_prompt
+            .replace("{SYS_PROMPT}", sys_prompt)
+            .replace("{TEXT}", text);
+        check_fits(self.backend.as_ref(), &self.config, stage, &intermediate_prompt)?;
+        let _ = self.events.send(PipelineEvent::IntermediateReduceStarted { index });
+
+        // Execute Reducer Context
+        let started = Instant::now();
+        let compressed_text = session.generate(&intermediate_prompt, &self.params, &mut |_| {})?;
+        let usage = Usage {
+            input_tokens: tokens,
+            output_tokens: self.backend.count_tokens(&compressed_text)?,
+            elapsed: started.elapsed(),
+        };
+
+        let entry = format!("[Intermediate Summary {}]\n{}\n\n", index, compressed_text);
+        let _ = self.events.send(PipelineEvent::IntermediateSummary { index, usage, text: compressed_text });
+        Ok(entry)
+    }
+}
+
+impl Tree {
+    fn new(fan_in: usize, workers: usize) -> Self {
+        Self { fan_in: fan_in.max(2), workers: workers.max(1), contexts: 1, entries: Vec::new(), tokens: 0, in_flight: 0, pool: None }
+    }
+
+    /// Lets the pool use a context per worker now that the map workers are done.
+    fn map_done(&mut self) {
+        self.contexts = self.workers;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.in_flight == 0
+    }
+
+    /// Adds a result of the current source. A group is only handed out once
+    /// the next result arrives, so a source of at most `fan_in` results that
+    /// fit the threshold goes straight to its final summary. An oversized
+    /// result is shortened on the reducer's own `session` first.
+    fn push(&mut self, reducer: &mut Reducer, session: &mut dyn InferenceSession, entry: &str) -> Result<()> {
+        let (entry, tokens) = reducer.shorten(session, entry)?;
+        if self.entries.len() == self.fan_in || (!self.entries.is_empty() && self.tokens + tokens > reducer.threshold) {
+            let group = std::mem::take(&mut self.entries).concat();
+            self.dispatch(reducer, group);
+            self.tokens = 0;
+        }
+        self.entries.push(entry);
+        self.tokens += tokens;
+        Ok(())
+    }
+
+    fn dispatch(&mut self, reducer: &mut Reducer, group: String) {
+        let sys_prompt = reducer.prompt();
+        let index = reducer.intermediate_count;
+        reducer.intermediate_count += 1;
+        self.in_flight += 1;
+        let pool = self.pool.get_or_insert_with(|| Pool::new(reducer.compressor.clone()));
+        pool.submit((index, sys_prompt, group), self.contexts.min(self.in_flight));
+    }
+
+    /// Waits for the groups in flight and returns their summaries in order.
+    fn collect(&mut self) -> Result<Vec<String>> {
+        let Some(Pool { done, .. }) = &self.pool else {
+            return Ok(Vec::new());
+        };
+        let mut summaries = Vec::with_capacity(self.in_flight);
+        for _ in 0..std::mem::take(&mut self.in_flight) {
+            summaries.push(done.recv().map_err(|_| Error::ThreadPanicked("reducer"))??);
+        }
+        summaries.sort_by_key(|(index, _)| *index);
+        Ok(summaries.into_iter().map(|(_, summary)| summary).collect())
+    }
+
+    /// Reduces the rest of the current source and returns the text for its final summary.
+    fn finish(&mut self, reducer: &mut Reducer) -> Result<Rolling> {
+        let mut level = std::mem::take(&mut self.entries);
+        self.tokens = 0;
+        if self.in_flight > 0 {
+            self.dispatch(reducer, level.concat());
+            level = self.collect()?;
+        }
+        loop {
+            let tokens = level.iter().map(|summary| reducer.backend.count_tokens(summary)).collect::<Result<Vec<_>>>()?;
+            if level.len() < 2 || (level.len() <= self.fan_in && tokens.iter().sum::<usize>() <= reducer.threshold) {
+                break;
+            }
+            // Groups are cut like in `push`, but always take two summaries so
+            // every level gets shorter; `check_fits` stops an overflowing one
+            let mut group: Vec<String> = Vec::new();
+            let mut group_tokens = 0;
+            for (summary, tokens) in level.into_iter().zip(tokens) {
+                if group.len() == self.fan_in || (group.len() >= 2 && group_tokens + tokens > reducer.threshold) {
+                    self.dispatch(reducer, std::mem::take(&mut group).concat());
+                    group_tokens = 0;
                 }
-                
-                // Reset buffer with compressed memory
-                rolling_buffer = format!("[Intermediate Summary {}]\n{}\n\n", intermediate_count, compressed_text);
-                rolling_token_count = reducer_model.str_to_token(&rolling_buffer, llama_cpp_2::model::AddBos::Never).unwrap_or_default().len();
-                intermediate_count += 1;
+                group.push(summary);
+                group_tokens += tokens;
             }
+            self.dispatch(reducer, group.concat());
+            level = self.collect()?;
         }
+        // Frees the pool's contexts before the final summary
+        self.pool = None;
+        let buffer = level.concat();
+        Ok(Rolling { tokens: reducer.backend.count_tokens(&buffer)?, buffer })
     }
-    
-    // Final Output
-    if !rolling_buffer.is_empty() {
-         if dynamic_prompt.is_none() {
-             if let Some(rx) = &meta_prompt_rx {
-                 dynamic_prompt = Some(rx.recv().unwrap_or_else(|_| reducer_prompt.clone()));
-             } else {
-                 dynamic_prompt = Some(generate_meta_prompt(meta_model.clone(), meta_backend.clone(), sample_summaries.clone(), config.clone()));
-             }
-             println!("\n[Meta-Prompt Applied]: {}", dynamic_prompt.as_ref().unwrap());
-         }
-
-         println!("\n[Final Summary]");
-         let final_prompt = config.final_reduce_prompt
-            .replace("{SYS_PROMPT}", dynamic_prompt.as_ref().unwrap())
-            .replace("{TEXT}", &rolling_buffer);
-         reducer_ctx.clear_kv_cache();
-         let tokens = reducer_model.str_to_token(&final_prompt, llama_cpp_2::model::AddBos::Always).unwrap();
-         let mut n_eval = 0;
-         let mut last_batch_tokens = 0;
-         while n_eval < tokens.len() {
-             let chunk_size = std::cmp::min(tokens.len() - n_eval, config.batch_size_limit);
-             let mut batch = LlamaBatch::new(chunk_size, 1);
-             for i in 0..chunk_size {
-                 let token = tokens[n_eval + i].clone();
-                 let is_last = (n_eval + i) == (tokens.len() - 1);
-                 batch.add(token, (n_eval + i) as i32, &[0], is_last).unwrap();
-             }
-             reducer_ctx.decode(&mut batch).unwrap();
-             last_batch_tokens = chunk_size;
-             n_eval += chunk_size;
-         }
-         let mut batch = LlamaBatch::new(1, 1);
-         let mut n_cur = tokens.len() as i32;
-         let mut decoder = encoding_rs::UTF_8.new_decoder();
-         
-         let mut sampler = LlamaSampler::chain_simple([
-             LlamaSampler::temp(config.sample_temp),
-             LlamaSampler::top_k(config.sample_top_k),
-             LlamaSampler::top_p(config.sample_top_p, 1),
-             LlamaSampler::penalties(config.penalty_last_n, config.penalty_repeat, 0.05, 0.05),
-             LlamaSampler::dist(1234),
-         ]);
-         // Isolate history: only penalize newly generated tokens, not the input prompt.
-         // sampler.accept_many(&tokens);
-
-         loop {
-             let mut candidates_p = LlamaTokenDataArray::from_iter(reducer_ctx.candidates_ith(last_batch_tokens as i32 - 1), false);
-             last_batch_tokens = 1;
-             
-             candidates_p.apply_sampler(&mut sampler);
-             let new_token_id = candidates_p.selected_token().expect("Failed to sample token");
-             sampler.accept(new_token_id);
-             if new_token_id == reducer_model.token_eos() || n_cur >= config.max_generate_tokens { break; }
-             let token_str = crate::types::decode_token(&reducer_model, new_token_id, &mut decoder);
-             print!("{}", token_str);
-             io::stdout().flush().unwrap();
-             
-             batch.clear();
-             batch.add(new_token_id, n_cur, &[0], true).unwrap();
-             if reducer_ctx.decode(&mut batch).is_err() { break; }
-             n_cur += 1;
-         }
-         println!();
+}
+
+/// The buffer size that triggers an intermediate reduce: `reduce_threshold_tokens`,
+/// or what is left of the context once the largest reduce template, the
+/// system prompt and `reduce_output_tokens` are accounted for.
+fn reduce_threshold(backend: &dyn InferenceBackend, config: &AppConfig, system_prompt: &str) -> Result<usize> {
+    if let Some(threshold) = config.reduce_threshold_tokens {
+        return Ok(threshold);
+    }
+    let mut template = 0;
+    for prompt in [&config.intermediate_reduce_prompt, &config.final_reduce_prompt, &config.cross_file_reduce_prompt] {
+        let prompt = prompt.replace("{SYS_PROMPT}", "").replace("{TEXT}", "");
+        template = template.max(backend.count_tokens(&prompt)?);
+    }
+    // The meta prompt takes the place of the system prompt once it is known
+    let system = backend.count_tokens(system_prompt)?.max(config.meta_generate_tokens);
+    let reserved = template + system + config.reduce_output_tokens;
+    match config.context_tokens().checked_sub(reserved) {
+        Some(threshold) if threshold > 0 => Ok(threshold),
+        _ => Err(Error::Config(format!(
+            "a context of {} tokens leaves no room to reduce after {} template and system prompt tokens and reduce_output_tokens ({})",
+            config.context_tokens(),
+            template + system,
+            config.reduce_output_tokens
+        ))),
+    }
+}
+
+/// Fails before decoding if the `stage` prompt would leave less than
+/// `reduce_output_tokens` of the context for its summary, instead of letting
+/// the generation overflow it.
+pub fn check_fits(backend: &dyn InferenceBackend, config: &AppConfig, stage: &str, prompt: &str) -> Result<()> {
+    let tokens = backend.count_tokens(prompt)?;
+    let budget = config.context_tokens().saturating_sub(config.reduce_output_tokens);
+    if tokens > budget {
+        return Err(Error::Inference(format!(
+            "the {} prompt has {} tokens, but a context of {} tokens only leaves room for {} after reduce_output_tokens ({}) (raise main_ctx_size or lower reduce_threshold_tokens)",
+            stage,
+            tokens,
+            config.context_tokens(),
+            budget,
+            config.reduce_output_tokens
+        )));
+    }
+    Ok(())
+}
+
+impl Pool {
+    fn new(compressor: Compressor) -> Self {
+        let (jobs, job_rx) = unbounded();
+        let (done_tx, done) = unbounded();
+        Self { compressor, jobs: Some(jobs), job_rx, done_tx, done, threads: Vec::new() }
+    }
+
+    /// Queues `job`, starting another thread if fewer than `threads` run.
+    fn submit(&mut self, job: Job, threads: usize) {
+        if self.threads.len() < threads {
+            let compressor = self.compressor.clone();
+            let jobs = self.job_rx.clone();
+            let done = self.done_tx.clone();
+            self.threads.push(thread::spawn(move || {
+                let mut session = None;
+                for (index, sys_prompt, group) in jobs {
+                    // A panic must not leave the reducer waiting for this summary
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        if session.is_none() {
+                            session = Some(compressor.backend.new_session(compressor.config.main_ctx_size)?);
+                        }
+                        let session = session.as_mut().unwrap();
+                        let tokens = compressor.backend.count_tokens(&group)?;
+                        compressor.run(session.as_mut(), "tree reduce", index, &sys_prompt, &group, tokens)
+                    }))
+                    .unwrap_or(Err(Error::ThreadPanicked("reducer")));
+                    if done.send(result.map(|summary| (index, summary))).is_err() {
+                        break;
+                    }
+                }
+            }));
+        }
+        if let Some(jobs) = &self.jobs {
+            let _ = jobs.send(job);
+        }
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        // Groups not started yet are dropped (the reduce failed), so the
+        // threads exit after the one they are on
+        self.jobs = None;
+        while self.job_rx.try_recv().is_ok() {}
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Merges JSON map results in input order with `Merger` instead of
+/// summarizing them (see `MergeConfig`). Results that are not JSON count as failed.
+fn run_merge(
+    backend: Arc<dyn InferenceBackend>,
+    system_prompt: String,
+    input: ReducerInput,
+    config: Arc<AppConfig>,
+    events: Sender<PipelineEvent>,
+    per_source: bool,
+) -> Result<()> {
+    let mut in_order = InOrder::default();
+
+    let keys = &config.merge.keys;
+    let mut source_merger = Merger::new(keys);
+    let mut run_merger = Merger::new(keys);
+    let mut current_source: Option<Arc<Path>> = None;
+
+    // The overview is the only step that needs the model
+    let mut session = match config.merge.overview {
+        true => Some(backend.new_session(config.main_ctx_size)?),
+        false => None,
+    };
+    let params = GenerationParams::from_config(&config).with_max_new_tokens(config.reduce_output_tokens);
+    let mut finish = |merger: &Merger, source: Option<Option<Arc<Path>>>| -> Result<()> {
+        let data = merger.value();
+        let _ = events.send(PipelineEvent::MergedResult { source: source.clone().flatten(), data: data.clone() });
+        let Some(session) = session.as_mut() else {
+            return Ok(());
+        };
+
+        let text = serde_json::to_string_pretty(&data).unwrap_or_default();
+        let prompt = config.final_reduce_prompt
+            .replace("{SYS_PROMPT}", &system_prompt)
+            .replace("{TEXT}", &text);
+        check_fits(backend.as_ref(), &config, "merge overview", &prompt)?;
+        let started = Instant::now();
+        let overview = session.generate(&prompt, &params, &mut |piece| {
+            let text = piece.to_string();
+            let _ = events.send(match &source {
+                Some(source) => PipelineEvent::SourceSummaryDelta { source: source.clone(), text },
+                None => PipelineEvent::FinalSummaryDelta { text },
+            });
+        })?;
+        let usage = Usage {
+            input_tokens: backend.count_tokens(&text)?,
+            output_tokens: backend.count_tokens(&overview)?,
+            elapsed: started.elapsed(),
+        };
+        let _ = events.send(match source {
+            Some(source) => PipelineEvent::SourceSummary { source, usage, text: overview },
+            None => PipelineEvent::FinalSummary { usage, text: overview },
+        });
+        Ok(())
+    };
+
+    while in_order.recv(&input) {
+        while let Some((index, source, text)) = in_order.next_output() {
+            let Ok(value) = serde_json::from_str::<serde_json::Value>(&text) else {
+                in_order.reject(index);
+                continue;
+            };
+            if per_source && source != current_source && !source_merger.is_empty() {
+                finish(&source_merger, Some(current_source.take()))?;
+                source_merger = Merger::new(keys);
+            }
+            current_source = source;
+
+            if per_source {
+                source_merger.add(index, value.clone());
+            }
+            if !per_source || config.combined_summary {
+                run_merger.add(index, value);
+            }
+        }
+    }
+    let _ = events.send(PipelineEvent::Completeness(in_order.report));
+
+    if per_source && !source_merger.is_empty() {
+        finish(&source_merger, Some(current_source))?;
+    }
+    if !run_merger.is_empty() {
+        finish(&run_merger, None)?;
     }
+    Ok(())
 }
@@ -0,0 +1,549 @@
+use regex::Regex;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashSet};
+
+use crate::error::{Error, Result};
+
+/// Rules shared by every generated grammar (the same primitives as llama.cpp's
+/// `json_schema_to_grammar`).
+const PRIMITIVES: &str = r#"space ::= | " " | "\n" [ \t]{0,20}
+char ::= [^"\\\x7F\x00-\x1F] | [\\] (["\\bfnrt] | "u" [0-9a-fA-F]{4})
+integral-part ::= [0] | [1-9] [0-9]{0,15}
+decimal-part ::= [0-9]{1,16}
+string ::= "\"" char* "\"" space
+integer ::= "-"? integral-part space
+number ::= "-"? integral-part ("." decimal-part)? ([eE] [-+]? integral-part)? space
+boolean ::= ("true" | "false") space
+null ::= "null" space
+value ::= object | array | string | number | boolean | null
+object ::= "{" space (string ":" space value ("," space string ":" space value)*)? "}" space
+array ::= "[" space (value ("," space value)*)? "]" space
+"#;
+
+/// A JSON Schema that chunk results must follow: compiled to a GBNF grammar
+/// for the sampler, and checked again once the output is complete.
+///
+/// Supports the subset that maps onto a grammar: `type` (also as a list),
+/// `properties` / `required`, `items` with `minItems` / `maxItems`, `enum`,
+/// `const`, `anyOf` / `oneOf`, `$ref` into `definitions` / `$defs`, and
+/// string `minLength` / `maxLength`. `pattern`, numeric bounds and
+/// `additionalProperties: false` are only enforced by `validate`.
+#[derive(Debug, Clone)]
+pub struct Schema {
+    schema: Value,
+    grammar: String,
+}
+
+impl Schema {
+    /// Converts `schema`; constructs outside the supported subset are a
+    /// configuration error.
+    pub fn new(schema: Value) -> Result<Self> {
+        let mut converter = Converter { root: &schema, rules: BTreeMap::new(), refs: HashSet::new() };
+        let root = converter.visit(&schema, "root")?;
+        if root != "root" {
+            converter.rules.insert("root".to_string(), root);
+        }
+        let mut grammar = String::new();
+        for (name, rule) in &converter.rules {
+            grammar.push_str(&format!("{} ::= {}\n", name, rule));
+        }
+        grammar.push_str(PRIMITIVES);
+        Ok(Self { schema, grammar })
+    }
+
+    /// The GBNF grammar, with its start rule named `root`.
+    pub fn grammar(&self) -> &str {
+        &self.grammar
+    }
+
+    /// Parses `text` as JSON and checks it against the schema, returning
+    /// every violation (e.g. output cut off by the token limit).
+    pub fn validate(&self, text: &str) -> std::result::Result<Value, Vec<String>> {
+        let value: Value = serde_json::from_str(text.trim()).map_err(|e| vec![format!("invalid JSON: {}", e)])?;
+        let mut errors = Vec::new();
+        check(&self.schema, &self.schema, &value, "$", &mut errors);
+        if errors.is_empty() {
+            Ok(value)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+struct Converter<'a> {
+    root: &'a Value,
+    rules: BTreeMap<String, String>,
+    /// `$ref` rules already generated (or being generated, for recursion).
+    refs: HashSet<String>,
+}
+
+impl<'a> Converter<'a> {
+    /// Returns a GBNF expression for `schema`, adding named rules for the
+    /// parts below it.
+    fn visit(&mut self, schema: &'a Value, name: &str) -> Result<String> {
+        let object = match schema {
+            Value::Bool(true) => return Ok("value".to_string()),
+            Value::Object(object) => object,
+            _ => return Err(unsupported(name, "schemas must be objects")),
+        };
+
+        if let Some(reference) = object.get("$ref").and_then(Value::as_str) {
+            return self.visit_ref(reference);
+        }
+        if let Some(value) = object.get("const") {
+            return Ok(format!("{} space", literal(&value.to_string())));
+        }
+        if let Some(values) = object.get("enum").and_then(Value::as_array) {
+            let alternatives: Vec<String> = values.iter().map(|v| literal(&v.to_string())).collect();
+            return Ok(format!("({}) space", alternatives.join(" | ")));
+        }
+        for keyword in ["anyOf", "oneOf"] {
+            if let Some(schemas) = object.get(keyword).and_then(Value::as_array) {
+                return self.alternatives(schemas.iter(), name);
+            }
+        }
+        if object.contains_key("allOf") || object.contains_key("not") {
+            return Err(unsupported(name, "allOf and not cannot be converted to a grammar"));
+        }
+
+        match object.get("type") {
+            None if object.contains_key("properties") => self.visit_object(object, name),
+            None if object.contains_key("items") => self.visit_array(object, name),
+            None => Ok("value".to_string()),
+            Some(Value::String(kind)) => self.visit_type(kind, object, name),
+            Some(Value::Array(kinds)) => {
+                let mut alternatives = Vec::new();
+                for kind in kinds {
+                    let kind = kind.as_str().ok_or_else(|| unsupported(name, "type must be a string"))?;
+                    alternatives.push(self.visit_type(kind, object, &format!("{}-{}", name, kind))?);
+                }
+                Ok(format!("({})", alternatives.join(" | ")))
+            }
+            Some(_) => Err(unsupported(name, "type must be a string or a list of strings")),
+        }
+    }
+
+    fn visit_type(&mut self, kind: &str, object: &'a Map<String, Value>, name: &str) -> Result<String> {
+        match kind {
+            "object" => self.visit_object(object, name),
+            "array" => self.visit_array(object, name),
+            "string" => {
+                let min = object.get("minLength").and_then(Value::as_u64).unwrap_or(0);
+                match object.get("maxLength").and_then(Value::as_u64) {
+                    Some(max) => Ok(format!("\"\\\"\" char{{{},{}}} \"\\\"\" space", min, max)),
+                    None if min > 0 => Ok(format!("\"\\\"\" char{{{},}} \"\\\"\" space", min)),
+                    None => Ok("string".to_string()),
+                }
+            }
+            "integer" | "number" | "boolean" | "null" => Ok(kind.to_string()),
+            _ => Err(unsupported(name, &format!("unknown type `{}`", kind))),
+        }
+    }
+
+    fn visit_object(&mut self, object: &'a Map<String, Value>, name: &str) -> Result<String> {
+        let Some(properties) = object.get("properties").and_then(Value::as_object) else {
+            return Ok("object".to_string());
+        };
+        let required: HashSet<&str> = object
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|names| names.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+
+        let mut mandatory = Vec::new();
+        let mut optional = Vec::new();
+        for (key, schema) in properties {
+            let rule = self.visit(schema, &format!("{}-{}", name, sanitize(key)))?;
+            let property = format!("{} space \":\" space {}", literal(&Value::from(key.as_str()).to_string()), rule);
+            if required.contains(key.as_str()) {
+                mandatory.push(property);
+            } else {
+                optional.push(property);
+            }
+        }
+
+        let mut body = mandatory.join(" \",\" space ");
+        if mandatory.is_empty() {
+            // Any subset of the optional properties, in order: pick the first
+            // one present, then each later one may follow
+            let starts: Vec<String> = (0..optional.len())
+                .map(|i| {
+                    let rest: String = optional[i + 1..].iter().map(|p| format!(" (\",\" space {})?", p)).collect();
+                    format!("{}{}", optional[i], rest)
+                })
+                .collect();
+            if !starts.is_empty() {
+                body = format!("({})?", starts.join(" | "));
+            }
+        } else {
+            for property in &optional {
+                body.push_str(&format!(" (\",\" space {})?", property));
+            }
+        }
+        Ok(self.add_rule(name, format!("\"{{\" space {} \"}}\" space", body)))
+    }
+
+    fn visit_array(&mut self, object: &'a Map<String, Value>, name: &str) -> Result<String> {
+        let item = match object.get("items") {
+            Some(items) => self.visit(items, &format!("{}-item", name))?,
+            None => "value".to_string(),
+        };
+        let min = object.get("minItems").and_then(Value::as_u64).unwrap_or(0);
+        let max = object.get("maxItems").and_then(Value::as_u64);
+        let more = format!("(\",\" space {})", item);
+        let body = match (min, max) {
+            (_, Some(0)) => String::new(),
+            (0, None) => format!("({} {}*)?", item, more),
+            (1, None) => format!("{} {}*", item, more),
+            (0, Some(max)) => format!("({} {}{{0,{}}})?", item, more, max - 1),
+            (min, None) => format!("{} {}{{{},}}", item, more, min - 1),
+            (min, Some(max)) => format!("{} {}{{{},{}}}", item, more, min - 1, max.max(min) - 1),
+        };
+        Ok(self.add_rule(name, format!("\"[\" space {} \"]\" space", body)))
+    }
+
+    fn visit_ref(&mut self, reference: &str) -> Result<String> {
+        let path = reference
+            .strip_prefix("#/definitions/")
+            .or_else(|| reference.strip_prefix("#/$defs/"))
+            .ok_or_else(|| unsupported(reference, "only local #/definitions and #/$defs references are supported"))?;
+        let name = format!("ref-{}", sanitize(path));
+        if self.refs.insert(name.clone()) {
+            let container = if reference.starts_with("#/$defs/") { "$defs" } else { "definitions" };
+            let root: &'a Value = self.root;
+            let target = root
+                .get(container)
+                .and_then(|defs| defs.get(path))
+                .ok_or_else(|| unsupported(reference, "reference target not found"))?;
+            let rule = self.visit(target, &name)?;
+            // Objects and arrays already registered themselves under `name`
+            if rule != name {
+                self.rules.insert(name.clone(), rule);
+            }
+        }
+        Ok(name)
+    }
+
+    fn alternatives(&mut self, schemas: impl Iterator<Item = &'a Value>, name: &str) -> Result<String> {
+        let mut alternatives = Vec::new();
+        for (i, schema) in schemas.enumerate() {
+            alternatives.push(self.visit(schema, &format!("{}-{}", name, i))?);
+        }
+        Ok(format!("({})", alternatives.join(" | ")))
+    }
+
+    /// Registers `rule` under `name` (made unique) and returns the name.
+    fn add_rule(&mut self, name: &str, rule: String) -> String {
+        let mut unique = name.to_string();
+        let mut n = 1;
+        while self.rules.get(&unique).is_some_and(|existing| *existing != rule) {
+            unique = format!("{}{}", name, n);
+            n += 1;
+        }
+        self.rules.insert(unique.clone(), rule);
+        unique
+    }
+}
+
+fn unsupported(at: &str, reason: &str) -> Error {
+    Error::Config(format!("unsupported JSON Schema at {}: {}", at, reason))
+}
+
+/// Rule names may only contain letters, digits and dashes.
+fn sanitize(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '-' }).collect()
+}
+
+/// A GBNF string literal matching `text` exactly.
+fn literal(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Appends a message for every way `value` (at `path`) violates `schema`.
+fn check(root: &Value, schema: &Value, value: &Value, path: &str, errors: &mut Vec<String>) {
+    let Value::Object(object) = schema else {
+        return;
+    };
+
+    if let Some(reference) = object.get("$ref").and_then(Value::as_str) {
+        let target = reference
+            .strip_prefix("#/definitions/")
+            .map(|name| ("definitions", name))
+            .or_else(|| reference.strip_prefix("#/$defs/").map(|name| ("$defs", name)))
+            .and_then(|(container, name)| root.get(container)?.get(name));
+        if let Some(target) = target {
+            check(root, target, value, path, errors);
+        }
+        return;
+    }
+    if let Some(expected) = object.get("const") {
+        if value != expected {
+            errors.push(format!("{}: expected {}", path, expected));
+        }
+    }
+    if let Some(values) = object.get("enum").and_then(Value::as_array) {
+        if !values.contains(value) {
+            errors.push(format!("{}: {} is not one of {}", path, value, Value::from(values.clone())));
+        }
+    }
+    for keyword in ["anyOf", "oneOf"] {
+        if let Some(schemas) = object.get(keyword).and_then(Value::as_array) {
+            let matches = schemas
+                .iter()
+                .filter(|schema| {
+                    let mut sub = Vec::new();
+                    check(root, schema, value, path, &mut sub);
+                    sub.is_empty()
+                })
+                .count();
+            if matches == 0 || (keyword == "oneOf" && matches > 1) {
+                errors.push(format!("{}: matches {} of the {} alternatives", path, matches, keyword));
+            }
+        }
+    }
+
+    if let Some(kind) = object.get("type") {
+        let kinds: Vec<&str> = match kind {
+            Value::String(kind) => vec![kind.as_str()],
+            Value::Array(kinds) => kinds.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !kinds.iter().any(|kind| has_type(value, kind)) {
+            errors.push(format!("{}: expected {}, got {}", path, kinds.join(" or "), type_name(value)));
+            return;
+        }
+    }
+
+    match value {
+        Value::Object(fields) => {
+            let properties = object.get("properties").and_then(Value::as_object);
+            for name in object.get("required").and_then(Value::as_array).into_iter().flatten() {
+                if let Some(name) = name.as_str().filter(|name| !fields.contains_key(*name)) {
+                    errors.push(format!("{}: missing required property `{}`", path, name));
+                }
+            }
+            for (name, field) in fields {
+                match properties.and_then(|properties| properties.get(name)) {
+                    Some(schema) => check(root, schema, field, &format!("{}.{}", path, name), errors),
+                    None if object.get("additionalProperties") == Some(&Value::Bool(false)) => {
+                        errors.push(format!("{}: unexpected property `{}`", path, name));
+                    }
+                    None => {}
+                }
+            }
+        }
+        Value::Array(items) => {
+            let len = items.len() as u64;
+            if let Some(min) = object.get("minItems").and_then(Value::as_u64).filter(|&min| len < min) {
+                errors.push(format!("{}: expected at least {} items, got {}", path, min, len));
+            }
+            if let Some(max) = object.get("maxItems").and_then(Value::as_u64).filter(|&max| len > max) {
+                errors.push(format!("{}: expected at most {} items, got {}", path, max, len));
+            }
+            if let Some(schema) = object.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    check(root, schema, item, &format!("{}[{}]", path, i), errors);
+                }
+            }
+        }
+        Value::String(text) => {
+            let len = text.chars().count() as u64;
+            if object.get("minLength").and_then(Value::as_u64).is_some_and(|min| len < min)
+                || object.get("maxLength").and_then(Value::as_u64).is_some_and(|max| len > max)
+            {
+                errors.push(format!("{}: string length {} is out of range", path, len));
+            }
+            if let Some(pattern) = object.get("pattern").and_then(Value::as_str) {
+                if Regex::new(pattern).is_ok_and(|regex| !regex.is_match(text)) {
+                    errors.push(format!("{}: does not match pattern `{}`", path, pattern));
+                }
+            }
+        }
+        Value::Number(number) => {
+            let x = number.as_f64().unwrap_or_default();
+            let bound = |key: &str| object.get(key).and_then(Value::as_f64);
+            if bound("minimum").is_some_and(|min| x < min)
+                || bound("maximum").is_some_and(|max| x > max)
+                || bound("exclusiveMinimum").is_some_and(|min| x <= min)
+                || bound("exclusiveMaximum").is_some_and(|max| x >= max)
+            {
+                errors.push(format!("{}: {} is out of range", path, number));
+            }
+        }
+        _ => {}
+    }
+}
+
+fn has_type(value: &Value, kind: &str) -> bool {
+    match kind {
+        "integer" => value.as_i64().is_some() || value.as_u64().is_some(),
+        "number" => value.is_number(),
+        _ => type_name(value) == kind,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// The rule named `name` in `grammar`.
+    fn rule<'a>(grammar: &'a str, name: &str) -> &'a str {
+        let prefix = format!("{} ::= ", name);
+        grammar.lines().find_map(|line| line.strip_prefix(prefix.as_str())).unwrap_or_else(|| panic!("no rule `{}` in\n{}", name, grammar))
+    }
+
+    fn topics() -> Schema {
+        Schema::new(json!({
+            "type": "object",
+            "properties": {
+                "topic": { "type": "string", "maxLength": 20 },
+                "tone": { "enum": ["calm", "angry"] },
+                "people": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": { "name": { "type": "string" }, "age": { "type": "integer" } },
+                        "required": ["name"]
+                    },
+                    "maxItems": 3
+                }
+            },
+            "required": ["topic", "people"]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn object_properties_become_rules() {
+        let schema = topics();
+        let grammar = schema.grammar();
+
+        // Required properties in order, optional ones may follow
+        assert_eq!(
+            rule(grammar, "root"),
+            "\"{\" space \"\\\"people\\\"\" space \":\" space root-people \",\" space \"\\\"topic\\\"\" space \":\" space \"\\\"\" char{0,20} \"\\\"\" space (\",\" space \"\\\"tone\\\"\" space \":\" space (\"\\\"calm\\\"\" | \"\\\"angry\\\"\") space)? \"}\" space"
+        );
+        assert!(grammar.contains("\nspace ::= "));
+    }
+
+    #[test]
+    fn nested_arrays_and_objects_get_their_own_rules() {
+        let schema = topics();
+        let grammar = schema.grammar();
+
+        assert_eq!(
+            rule(grammar, "root-people"),
+            "\"[\" space (root-people-item (\",\" space root-people-item){0,2})? \"]\" space"
+        );
+        assert_eq!(
+            rule(grammar, "root-people-item"),
+            "\"{\" space \"\\\"name\\\"\" space \":\" space string (\",\" space \"\\\"age\\\"\" space \":\" space integer)? \"}\" space"
+        );
+    }
+
+    #[test]
+    fn enum_at_the_root_is_a_choice_of_literals() {
+        let schema = Schema::new(json!({ "enum": ["yes", "no", 1] })).unwrap();
+
+        assert_eq!(rule(schema.grammar(), "root"), "(\"\\\"yes\\\"\" | \"\\\"no\\\"\" | \"1\") space");
+    }
+
+    #[test]
+    fn references_are_converted_once() {
+        let schema = Schema::new(json!({
+            "type": "array",
+            "items": { "$ref": "#/$defs/item" },
+            "$defs": { "item": { "type": "object", "properties": { "next": { "$ref": "#/$defs/item" } } } }
+        }))
+        .unwrap();
+        let grammar = schema.grammar();
+
+        assert_eq!(rule(grammar, "root"), "\"[\" space (ref-item (\",\" space ref-item)*)? \"]\" space");
+        assert!(rule(grammar, "ref-item").contains("ref-item"));
+    }
+
+    #[test]
+    fn unsupported_schemas_are_configuration_errors() {
+        assert!(matches!(Schema::new(json!({ "allOf": [] })), Err(Error::Config(_))));
+        assert!(matches!(Schema::new(json!({ "type": "tuple" })), Err(Error::Config(_))));
+        assert!(matches!(Schema::new(json!({ "$ref": "http://example.com/schema" })), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn valid_output_is_accepted() {
+        let value = topics().validate(" {\"topic\": \"猫\", \"tone\": \"calm\", \"people\": [{\"name\": \"吾輩\", \"age\": 2}]}\n").unwrap();
+
+        assert_eq!(value["people"][0]["name"], "吾輩");
+    }
+
+    #[test]
+    fn invalid_output_lists_every_violation() {
+        let errors = topics()
+            .validate("{\"topic\": \"猫\", \"tone\": \"sad\", \"people\": [{\"age\": 2.5}, {\"name\": 1}, {\"name\": \"a\"}, {\"name\": \"b\"}]}")
+            .unwrap_err();
+
+        assert_eq!(
+            errors,
+            vec![
+                "$.people: expected at most 3 items, got 4",
+                "$.people[0]: missing required property `name`",
+                "$.people[0].age: expected integer, got number",
+                "$.people[1].name: expected string, got number",
+                "$.tone: \"sad\" is not one of [\"calm\",\"angry\"]",
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_output_is_rejected() {
+        let errors = topics().validate("{\"topic\": \"猫\", \"peo").unwrap_err();
+
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("invalid JSON: "), "{}", errors[0]);
+    }
+
+    #[test]
+    fn validation_checks_what_the_grammar_cannot() {
+        let schema = Schema::new(json!({
+            "type": "object",
+            "properties": { "date": { "type": "string", "pattern": "^\\d{4}-\\d{2}$" }, "score": { "type": "number", "minimum": 0 } },
+            "additionalProperties": false
+        }))
+        .unwrap();
+
+        assert!(schema.validate("{\"date\": \"1905-01\", \"score\": 0.5}").is_ok());
+        assert_eq!(
+            schema.validate("{\"date\": \"明治38年\", \"score\": -1, \"extra\": true}").unwrap_err(),
+            vec![
+                "$.date: does not match pattern `^\\d{4}-\\d{2}$`",
+                "$: unexpected property `extra`",
+                "$.score: -1 is out of range",
+            ]
+        );
+    }
+}
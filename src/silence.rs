@@ -0,0 +1,180 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::config::STRUCTURED_SILENCE_PROMPT;
+use crate::error::{Error, Result};
+
+/// A pattern that marks a chunk result as having nothing to report.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sentinel {
+    /// Literal text, e.g. `{"exact": "特になし"}`.
+    Exact(String),
+    /// A regular expression, e.g. `{"regex": "(?i)^n/?a$"}`.
+    Regex(String),
+}
+
+/// When a worker result counts as silent (the "Rule of Silence").
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SilenceRules {
+    /// A result matching any of these is silent.
+    pub sentinels: Vec<Sentinel>,
+    /// Only silence results that consist of a sentinel and nothing else, so
+    /// an output that merely quotes one is kept.
+    pub whole_output: bool,
+    /// Ask the model to start with a `NOTABLE: yes|no` line (see
+    /// `structured_prompt`) and decide on that; the sentinels remain the
+    /// fallback when the line is missing.
+    pub structured: bool,
+    /// Appended to the system prompt of the workers in structured mode.
+    pub structured_prompt: String,
+}
+
+impl Default for SilenceRules {
+    fn default() -> Self {
+        Self {
+            sentinels: vec![
+                Sentinel::Exact("特になし".to_string()),
+                Sentinel::Regex(r"(?i)^(nothing (notable|to report)|n/a|none)[.。]?$".to_string()),
+            ],
+            whole_output: false,
+            structured: false,
+            structured_prompt: STRUCTURED_SILENCE_PROMPT.to_string(),
+        }
+    }
+}
+
+impl SilenceRules {
+    /// Compiles the sentinels; an invalid regex is a configuration error.
+    pub fn matcher(&self) -> Result<SilenceMatcher> {
+        let sentinels = self
+            .sentinels
+            .iter()
+            .map(|sentinel| match sentinel {
+                Sentinel::Exact(text) => Ok(Matcher::Exact(text.clone())),
+                Sentinel::Regex(pattern) => Regex::new(pattern)
+                    .map(Matcher::Regex)
+                    .map_err(|e| Error::Config(format!("invalid silence sentinel `{}`: {}", pattern, e))),
+            })
+            .collect::<Result<_>>()?;
+        Ok(SilenceMatcher { sentinels, whole_output: self.whole_output, structured: self.structured })
+    }
+}
+
+enum Matcher {
+    Exact(String),
+    Regex(Regex),
+}
+
+/// Compiled `SilenceRules`.
+pub struct SilenceMatcher {
+    sentinels: Vec<Matcher>,
+    whole_output: bool,
+    structured: bool,
+}
+
+impl SilenceMatcher {
+    /// The text to report for a raw worker output, or `None` if it is silent.
+    pub fn filter<'a>(&self, output: &'a str) -> Option<&'a str> {
+        let mut text = output.trim();
+        if self.structured {
+            if let Some((notable, rest)) = notable_field(text) {
+                if !notable {
+                    return None;
+                }
+                text = rest.trim();
+            }
+        }
+        (!text.is_empty() && !self.is_sentinel(text)).then_some(text)
+    }
+
+    fn is_sentinel(&self, text: &str) -> bool {
+        self.sentinels.iter().any(|sentinel| match sentinel {
+            Matcher::Exact(sentinel) if self.whole_output => text.trim_end_matches(['。', '.']) == sentinel,
+            Matcher::Exact(sentinel) => text.contains(sentinel.as_str()),
+            Matcher::Regex(regex) if self.whole_output => {
+                regex.find(text).is_some_and(|m| m.start() == 0 && m.end() == text.len())
+            }
+            Matcher::Regex(regex) => regex.is_match(text),
+        })
+    }
+}
+
+/// Parses a leading `NOTABLE: yes|no` line (case-insensitive, full-width
+/// colon allowed), returning the answer and the text after that line.
+fn notable_field(text: &str) -> Option<(bool, &str)> {
+    let (first, rest) = text.split_once('\n').unwrap_or((text, ""));
+    let line = first.trim().trim_matches(['*', '`']);
+    let (key, value) = line.split_once([':', '：'])?;
+    if !key.trim().trim_matches(['*', '`']).eq_ignore_ascii_case("notable") {
+        return None;
+    }
+    let value = value.trim().trim_matches(['*', '`', '.']).to_ascii_lowercase();
+    match value.as_str() {
+        "yes" | "true" => Some((true, rest)),
+        "no" | "false" => Some((false, rest)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(rules: SilenceRules) -> SilenceMatcher {
+        rules.matcher().unwrap()
+    }
+
+    #[test]
+    fn default_regex_matches_english_sentinels() {
+        let silence = matcher(SilenceRules::default());
+        for output in ["Nothing notable.", "nothing to report", "N/A", "n/a。", "None", "  NONE\n"] {
+            assert_eq!(silence.filter(output), None, "{:?}", output);
+        }
+        // The regex is anchored, so a sentence that merely contains the words is kept
+        for output in ["None of the requests failed.", "There was nothing notable until 12:00, then the disk filled up."] {
+            assert_eq!(silence.filter(output), Some(output), "{:?}", output);
+        }
+    }
+
+    #[test]
+    fn whole_output_keeps_a_quoted_sentinel() {
+        let output = "ユーザーは「特になし」と回答したが、ログにはエラーが残っている。";
+        assert_eq!(matcher(SilenceRules::default()).filter(output), None);
+
+        let silence = matcher(SilenceRules { whole_output: true, ..SilenceRules::default() });
+        assert_eq!(silence.filter(output), Some(output));
+        assert_eq!(silence.filter("The reply was \"N/A\"."), Some("The reply was \"N/A\"."));
+        assert_eq!(silence.filter("特になし。"), None);
+        assert_eq!(silence.filter("n/a"), None);
+    }
+
+    #[test]
+    fn structured_notable_field_decides() {
+        let silence = matcher(SilenceRules { structured: true, ..SilenceRules::default() });
+        // The NOTABLE line is stripped from the reported text
+        assert_eq!(silence.filter("NOTABLE: yes\nディスクが満杯になった。"), Some("ディスクが満杯になった。"));
+        assert_eq!(silence.filter("**Notable**：Yes.\n\nThe disk filled up."), Some("The disk filled up."));
+        // "no" is silent whatever follows it
+        assert_eq!(silence.filter("NOTABLE: no\nディスクが満杯になった。"), None);
+        assert_eq!(silence.filter("notable: false"), None);
+        // "yes" with nothing after it has nothing to report either
+        assert_eq!(silence.filter("NOTABLE: yes\n"), None);
+    }
+
+    #[test]
+    fn missing_notable_field_falls_back_to_the_sentinels() {
+        let silence = matcher(SilenceRules { structured: true, ..SilenceRules::default() });
+        assert_eq!(silence.filter("ディスクが満杯になった。"), Some("ディスクが満杯になった。"));
+        assert_eq!(silence.filter("特になし"), None);
+        // An unknown answer is not a NOTABLE field, so the line is kept
+        assert_eq!(silence.filter("NOTABLE: maybe\nThe disk filled up."), Some("NOTABLE: maybe\nThe disk filled up."));
+    }
+
+    #[test]
+    fn invalid_regex_is_a_config_error() {
+        let rules = SilenceRules { sentinels: vec![Sentinel::Regex("(unclosed".to_string())], ..SilenceRules::default() };
+        assert!(matches!(rules.matcher(), Err(Error::Config(_))));
+    }
+}
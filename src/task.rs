@@ -0,0 +1,147 @@
+use serde_json::json;
+
+use crate::config::*;
+use crate::error::{Error, Result};
+
+// Task Prompts ({LABELS}, {LANGUAGE} and {QUESTION} are filled in by `Task::configure`)
+pub const EXTRACT_SYSTEM_PROMPT: &str = "テキストに含まれる該当項目をすべて抽出してください。";
+pub const EXTRACT_WORKER_PROMPT: &str = "<|startoftext|><|im_start|>system\n{SYS_PROMPT}\nあなたは入力テキストから情報を抽出するAIです。テキストに書かれている事実だけを、指定された JSON 形式で出力してください。書かれていない項目を推測で補わないでください。<|im_end|>\n<|im_start|>user\n以下のテキストから情報を抽出してください。\n\n{TEXT}<|im_end|>\n<|im_start|>assistant\n";
+
+pub const CLASSIFY_SYSTEM_PROMPT: &str = "テキストの主題に基づいて分類してください。";
+pub const CLASSIFY_WORKER_PROMPT: &str = "<|startoftext|><|im_start|>system\n{SYS_PROMPT}\nあなたはテキストを分類するAIです。次のラベルから最も当てはまるものを1つだけ選んでください: {LABELS}<|im_end|>\n<|im_start|>user\n以下のテキストを分類してください。\n\n{TEXT}<|im_end|>\n<|im_start|>assistant\n";
+
+pub const TRANSLATE_SYSTEM_PROMPT: &str = "原文の意味と語調を保って翻訳してください。";
+pub const TRANSLATE_WORKER_PROMPT: &str = "<|startoftext|><|im_start|>system\n{SYS_PROMPT}\nあなたは翻訳者です。入力テキストを{LANGUAGE}に翻訳し、訳文のみを出力してください。要約・省略・注釈は一切しないでください。<|im_end|>\n<|im_start|>user\n以下のテキストを{LANGUAGE}に翻訳してください。\n\n{TEXT}<|im_end|>\n<|im_start|>assistant\n";
+pub const TRANSLATE_OVERLAP_TEMPLATE: &str = "[前のチャンクからの文脈（参照のみ・翻訳しないこと）]\n{OVERLAP}\n[本文]\n{TEXT}";
+
+pub const ASK_SYSTEM_PROMPT: &str = "テキストの記述だけを根拠に、正確に答えてください。";
+pub const ASK_WORKER_PROMPT: &str = "<|startoftext|><|im_start|>system\n{SYS_PROMPT}\nあなたはテキストから質問に関係する記述を探すAIです。\n質問: {QUESTION}<|im_end|>\n<|im_start|>user\n以下のテキストに質問と関係する記述があれば、その内容を簡潔に書き出してください。関係する記述がなければ「特になし」とだけ出力してください。\n\n{TEXT}<|im_end|>\n<|im_start|>assistant\n";
+pub const ASK_INTERMEDIATE_PROMPT: &str = "<|startoftext|><|im_start|>system\n{SYS_PROMPT}<|im_end|>\n<|im_start|>user\n以下は質問「{QUESTION}」に関係する記述です。重複を除いて統合し、回答に必要な情報を漏らさず残してください。\n\n{TEXT}<|im_end|>\n<|im_start|>assistant\n";
+pub const ASK_FINAL_PROMPT: &str = "<|startoftext|><|im_start|>system\n{SYS_PROMPT}<|im_end|>\n<|im_start|>user\n以下の記述だけを根拠に、質問「{QUESTION}」に答えてください。記述から分からないことは分からないと答えてください。\n\n{TEXT}<|im_end|>\n<|im_start|>assistant\n";
+
+/// A task preset: the prompts, silence rules and stages for one kind of job.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Task {
+    /// Summarize the input (the default templates).
+    Summarize,
+    /// Extract JSON matching `AppConfig::schema` from every chunk and merge it.
+    Extract,
+    /// Label every chunk with one of `labels`; there is no reduce.
+    Classify { labels: Vec<String> },
+    /// Translate every chunk into `language`, in input order; there is no
+    /// reduce and no chunk is ever silent.
+    Translate { language: String },
+    /// Keep only what the chunks say about `question`, then answer it.
+    Ask { question: String },
+}
+
+impl Task {
+    /// Applies the task's templates, silence rules and merge settings.
+    pub fn configure(&self, config: &mut AppConfig) {
+        match self {
+            Task::Summarize => {}
+            Task::Extract => {
+                config.worker_prompt_template = EXTRACT_WORKER_PROMPT.to_string();
+                config.merge.enabled = true;
+            }
+            Task::Classify { labels } => {
+                config.worker_prompt_template = CLASSIFY_WORKER_PROMPT.replace("{LABELS}", &labels.join(", "));
+                config.schema = Some(json!({
+                    "type": "object",
+                    "properties": { "label": { "enum": labels } },
+                    "required": ["label"],
+                }));
+                config.silence.sentinels.clear();
+            }
+            Task::Translate { language } => {
+                config.worker_prompt_template = TRANSLATE_WORKER_PROMPT.replace("{LANGUAGE}", language);
+                config.overlap_text_template = TRANSLATE_OVERLAP_TEMPLATE.to_string();
+                config.silence.sentinels.clear();
+            }
+            Task::Ask { question } => {
+                config.worker_prompt_template = ASK_WORKER_PROMPT.replace("{QUESTION}", question);
+                config.intermediate_reduce_prompt = ASK_INTERMEDIATE_PROMPT.replace("{QUESTION}", question);
+                config.final_reduce_prompt = ASK_FINAL_PROMPT.replace("{QUESTION}", question);
+                config.cross_file_reduce_prompt = ASK_FINAL_PROMPT.replace("{QUESTION}", question);
+                // Findings that merely mention "特になし" still count
+                config.silence.whole_output = true;
+            }
+        }
+    }
+
+    /// The system prompt used when none is given.
+    pub fn system_prompt(&self) -> &'static str {
+        match self {
+            Task::Summarize => DEFAULT_SYSTEM_PROMPT,
+            Task::Extract => EXTRACT_SYSTEM_PROMPT,
+            Task::Classify { .. } => CLASSIFY_SYSTEM_PROMPT,
+            Task::Translate { .. } => TRANSLATE_SYSTEM_PROMPT,
+            Task::Ask { .. } => ASK_SYSTEM_PROMPT,
+        }
+    }
+
+    /// Whether the chunk results are reduced at all.
+    pub fn reduces(&self) -> bool {
+        !matches!(self, Task::Classify { .. } | Task::Translate { .. })
+    }
+
+    /// Checks what the task needs from the final configuration.
+    pub fn check(&self, config: &AppConfig) -> Result<()> {
+        match self {
+            Task::Extract if config.schema.is_none() => {
+                Err(Error::Config("extract needs a JSON Schema (--schema or `schema` in the config)".to_string()))
+            }
+            Task::Classify { labels } if labels.is_empty() => {
+                Err(Error::Config("classify needs at least one label".to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn configured(task: &Task) -> AppConfig {
+        let mut config = AppConfig::default();
+        task.configure(&mut config);
+        config
+    }
+
+    #[test]
+    fn extract_needs_a_schema() {
+        let config = configured(&Task::Extract);
+        assert!(config.merge.enabled);
+        assert!(matches!(Task::Extract.check(&config), Err(Error::Config(_))));
+
+        let config = AppConfig { schema: Some(json!({ "type": "object" })), ..config };
+        assert!(Task::Extract.check(&config).is_ok());
+    }
+
+    #[test]
+    fn classify_builds_the_label_schema() {
+        let task = Task::Classify { labels: vec!["bug".to_string(), "question".to_string()] };
+        let config = configured(&task);
+
+        assert_eq!(config.schema.unwrap()["properties"]["label"]["enum"], json!(["bug", "question"]));
+        assert!(config.worker_prompt_template.contains("bug, question"));
+        // Every chunk gets a label, so none may be silenced
+        assert!(config.silence.sentinels.is_empty());
+        assert!(!task.reduces());
+        assert!(matches!(Task::Classify { labels: Vec::new() }.check(&AppConfig::default()), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn translate_keeps_every_chunk_and_skips_the_reduce() {
+        let task = Task::Translate { language: "English".to_string() };
+        let config = configured(&task);
+
+        assert!(!task.reduces());
+        assert!(config.silence.sentinels.is_empty());
+        assert!(config.worker_prompt_template.contains("English"));
+        assert!(!config.worker_prompt_template.contains("{LANGUAGE}"));
+        assert_eq!(config.overlap_text_template, TRANSLATE_OVERLAP_TEMPLATE);
+        assert!(Task::Summarize.reduces() && Task::Extract.reduces());
+    }
+}
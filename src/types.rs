@@ -1,6 +1,62 @@
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
 pub struct ChunkTask {
+    /// Position in the whole run; numbering continues across input files.
     pub index: usize,
+    /// The input file the chunk was read from (`None` for stdin).
+    pub source: Option<Arc<Path>>,
     pub text: String,
+    /// Byte length of the leading part of `text` repeated from the previous
+    /// chunk for context (see `AppConfig::chunk_overlap_tokens`).
+    pub overlap: usize,
+    /// Where the body lies in the decoded (and preprocessed) text of `source`;
+    /// the input bytes themselves for UTF-8 input without preprocessing.
+    pub byte_range: Range<usize>,
+    /// Token count of the body.
+    pub tokens: usize,
+}
+
+impl ChunkTask {
+    /// The context carried over from the previous chunk.
+    pub fn overlap_text(&self) -> &str {
+        &self.text[..self.overlap]
+    }
+
+    /// The part of the chunk that is new.
+    pub fn body(&self) -> &str {
+        &self.text[self.overlap..]
+    }
+}
+
+/// The map result of one chunk on its way to the reducer. Every chunk
+/// sends one, so the reducer never waits for a chunk that will not come.
+pub struct ChunkResult {
+    pub index: usize,
+    pub source: Option<Arc<Path>>,
+    pub status: ChunkStatus,
+}
+
+/// How the map of one chunk ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkStatus {
+    /// A result worth reducing.
+    Output(String),
+    /// Nothing to report (the Rule of Silence).
+    Silent,
+    /// No usable result, e.g. one that does not match the schema.
+    Failed,
+}
+
+/// Token counts and wall time of one generation step.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// Tokens of the text being summarized (without the prompt template).
+    pub input_tokens: usize,
+    pub output_tokens: usize,
+    pub elapsed: Duration,
 }
 
 pub fn decode_token(
@@ -1,108 +1,279 @@
-use crate::types::ChunkTask;
-use crossbeam_channel::Receiver;
-use llama_cpp_2::llama_backend::LlamaBackend;
-use llama_cpp_2::model::LlamaModel;
-use llama_cpp_2::context::params::LlamaContextParams;
-use llama_cpp_2::llama_batch::LlamaBatch;
-use llama_cpp_2::sampling::LlamaSampler;
-use llama_cpp_2::token::data_array::LlamaTokenDataArray;
-use std::num::NonZeroU32;
+use crate::types::{ChunkResult, ChunkStatus, ChunkTask, Usage};
+use crossbeam_channel::{Receiver, Sender};
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Instant;
+use crate::backend::InferenceBackend;
 use crate::config::*;
+use crate::error::Result;
+use crate::generator::GenerationParams;
+use crate::pipeline::{CompletenessReport, PipelineEvent};
+use crate::reducer::check_fits;
+use crate::schema::Schema;
 
 pub fn worker_loop(
     _worker_id: usize,
-    model: Arc<LlamaModel>,
-    backend: Arc<LlamaBackend>,
+    backend: Arc<dyn InferenceBackend>,
     rx: Receiver<ChunkTask>,
     system_prompt: String,
     config: Arc<AppConfig>,
-) -> Vec<(usize, String)> {
+    events: Sender<PipelineEvent>,
+    results: Sender<ChunkResult>,
+) -> Result<()> {
     // Each worker has its own context. This prevents locking during inference.
-    let ctx_params = LlamaContextParams::default()
-        .with_n_ctx(Some(NonZeroU32::new(config.main_ctx_size).unwrap()))
-        .with_n_batch(config.batch_size_limit as u32)
-        .with_n_ubatch(config.batch_size_limit as u32);
-    let mut ctx = model
-        .new_context(backend.as_ref(), ctx_params)
-        .expect("Failed to create context");
-
-    let mut outputs = Vec::new();
+    let mut session = backend.new_session(config.main_ctx_size)?;
+    let mut params = GenerationParams::from_config(&config);
+    let schema = config.schema.clone().map(Schema::new).transpose()?;
+    if let Some(schema) = &schema {
+        params = params.with_grammar(schema.grammar());
+    }
+    let silence = config.silence.matcher()?;
+    let system_prompt = if config.silence.structured {
+        format!("{}\n{}", system_prompt, config.silence.structured_prompt)
+    } else {
+        system_prompt
+    };
 
     for task in rx {
-        // Clear the cache from previous chunk processing to prevent overflow and overlap
-        ctx.clear_kv_cache();
-
         // Build the prompt for the model using LFM2.5 ChatML template
         let prompt = config.worker_prompt_template
             .replace("{SYS_PROMPT}", &system_prompt)
-            .replace("{TEXT}", &task.text);
-        
-        let tokens = model
-            .str_to_token(&prompt, llama_cpp_2::model::AddBos::Always)
-            .expect("Failed to tokenize prompt");
-
-        let mut n_eval = 0;
-        let mut last_batch_tokens = 0;
-        while n_eval < tokens.len() {
-            let chunk_size = std::cmp::min(tokens.len() - n_eval, config.batch_size_limit);
-            let mut batch = LlamaBatch::new(chunk_size, 1);
-            for i in 0..chunk_size {
-                let token = tokens[n_eval + i].clone();
-                let is_last = (n_eval + i) == (tokens.len() - 1);
-                batch.add(token, (n_eval + i) as i32, &[0], is_last).expect("Failed to add to batch");
+            .replace("{TEXT}", &task_text(&config, &task));
+
+        let started = Instant::now();
+        let generated = session
+            .generate(&prompt, &params, &mut |_| {})
+            .and_then(|text| Ok((backend.count_tokens(&text)?, text)));
+        let (output_tokens, generated_text) = match generated {
+            Ok(generated) => generated,
+            Err(e) => {
+                // Reported as failed, not missing, before the run stops
+                let _ = results.send(ChunkResult { index: task.index, source: task.source, status: ChunkStatus::Failed });
+                return Err(e);
             }
-            ctx.decode(&mut batch).expect("Failed to decode prompt chunk");
-            last_batch_tokens = chunk_size;
-            n_eval += chunk_size;
+        };
+        let usage = Usage {
+            input_tokens: task.tokens,
+            output_tokens,
+            elapsed: started.elapsed(),
+        };
+
+        // The grammar cannot stop the token limit from cutting the JSON short
+        if let Some(Err(errors)) = schema.as_ref().map(|schema| schema.validate(&generated_text)) {
+            let _ = events.send(PipelineEvent::InvalidChunk {
+                index: task.index,
+                source: task.source.clone(),
+                byte_range: task.byte_range,
+                usage,
+                text: generated_text,
+                errors,
+            });
+            let _ = results.send(ChunkResult { index: task.index, source: task.source, status: ChunkStatus::Failed });
+            continue;
         }
 
-        let mut batch = LlamaBatch::new(1, 1);
-        let mut generated_text = String::new();
-        let mut n_cur = tokens.len() as i32;
-        let mut decoder = encoding_rs::UTF_8.new_decoder();
-        
-        let mut sampler = LlamaSampler::chain_simple([
-            LlamaSampler::temp(config.sample_temp),
-            LlamaSampler::top_k(config.sample_top_k),
-            LlamaSampler::top_p(config.sample_top_p, 1),
-            LlamaSampler::penalties(config.penalty_last_n, config.penalty_repeat, 0.05, 0.05),
-            LlamaSampler::dist(1234),
-        ]);
-        // Isolate history: only penalize newly generated tokens, not the input prompt.
-        // sampler.accept_many(&tokens);
-        
-        loop {
-            let candidates = ctx.candidates_ith(last_batch_tokens as i32 - 1);
-            last_batch_tokens = 1;
-            let mut candidates_p = LlamaTokenDataArray::from_iter(candidates, false);
-            
-            candidates_p.apply_sampler(&mut sampler);
-            let new_token_id = candidates_p.selected_token().expect("Failed to sample token");
-            sampler.accept(new_token_id);
-
-            // Check if end of generation
-            if new_token_id == model.token_eos() || n_cur >= config.max_generate_tokens {
-                break;
+        // Rule of Silence: emit only if output is notable
+        if let Some(text) = silence.filter(&generated_text) {
+            let _ = events.send(PipelineEvent::Chunk {
+                index: task.index,
+                source: task.source.clone(),
+                byte_range: task.byte_range,
+                usage,
+                text: text.to_string(),
+            });
+            let status = ChunkStatus::Output(text.to_string());
+            // Blocks while the reducer is busy, which in turn holds back the chunker
+            let _ = results.send(ChunkResult { index: task.index, source: task.source, status });
+        } else {
+            let _ = events.send(PipelineEvent::SilentChunk {
+                index: task.index,
+                source: task.source.clone(),
+                byte_range: task.byte_range,
+                usage,
+            });
+            let _ = results.send(ChunkResult { index: task.index, source: task.source, status: ChunkStatus::Silent });
+        }
+    }
+
+    Ok(())
+}
+
+/// The refine strategy: one context works through the chunks in input order,
+/// each together with the summary of everything before it, so the summary
+/// evolves with the text instead of being reduced from independent results.
+/// Every step is reported as its chunk's result; the last one of a source is
+/// its summary. Silent and invalid steps keep the previous summary. Steps are
+/// checked and capped like reduce steps, since the summary is carried along.
+pub fn refine_loop(
+    backend: Arc<dyn InferenceBackend>,
+    rx: Receiver<ChunkTask>,
+    system_prompt: String,
+    config: Arc<AppConfig>,
+    events: Sender<PipelineEvent>,
+    per_source: bool,
+) -> Result<()> {
+    let mut session = backend.new_session(config.main_ctx_size)?;
+    let mut params = GenerationParams::from_config(&config).with_max_new_tokens(config.reduce_output_tokens);
+    let schema = config.schema.clone().map(Schema::new).transpose()?;
+    if let Some(schema) = &schema {
+        params = params.with_grammar(schema.grammar());
+    }
+    let silence = config.silence.matcher()?;
+    let system_prompt = if config.silence.structured {
+        format!("{}\n{}", system_prompt, config.silence.structured_prompt)
+    } else {
+        system_prompt
+    };
+
+    let mut report = CompletenessReport::default();
+    let mut summary = String::new();
+    let mut current_source: Option<Arc<Path>> = None;
+    let mut source_summaries = Vec::new();
+
+    for task in rx {
+        if per_source && task.source != current_source && !summary.is_empty() {
+            let source = current_source.take();
+            send_source_summary(&events, source.clone(), &summary);
+            source_summaries.push((source, std::mem::take(&mut summary)));
+        }
+        current_source = task.source.clone();
+
+        // The first chunk has nothing to refine yet
+        let template = match summary.is_empty() {
+            true => &config.worker_prompt_template,
+            false => &config.refine_prompt_template,
+        };
+        let text = task_text(&config, &task);
+        let prompt = fill(template, &[("{SYS_PROMPT}", &system_prompt), ("{TEXT}", &text), ("{PREV_SUMMARY}", &summary)]);
+
+        let started = Instant::now();
+        let generated = check_fits(backend.as_ref(), &config, "refine", &prompt)
+            .and_then(|()| session.generate(&prompt, &params, &mut |_| {}))
+            .and_then(|text| Ok((backend.count_tokens(&text)?, text)));
+        let (output_tokens, generated_text) = match generated {
+            Ok(generated) => generated,
+            Err(e) => {
+                // Reported as failed, not missing, before the run stops
+                report.failed.push(task.index);
+                let _ = events.send(PipelineEvent::Completeness(report));
+                return Err(e);
             }
+        };
+        let usage = Usage {
+            input_tokens: task.tokens,
+            output_tokens,
+            elapsed: started.elapsed(),
+        };
 
-            let token_str = crate::types::decode_token(&model, new_token_id, &mut decoder);
-            generated_text.push_str(&token_str);
+        if let Some(Err(errors)) = schema.as_ref().map(|schema| schema.validate(&generated_text)) {
+            let _ = events.send(PipelineEvent::InvalidChunk {
+                index: task.index,
+                source: task.source,
+                byte_range: task.byte_range,
+                usage,
+                text: generated_text,
+                errors,
+            });
+            report.failed.push(task.index);
+            continue;
+        }
 
-            batch.clear();
-            batch.add(new_token_id, n_cur, &[0], true).unwrap();
-            
-            ctx.decode(&mut batch).expect("Failed to decode next token");
-            n_cur += 1;
+        if let Some(text) = silence.filter(&generated_text) {
+            summary = text.to_string();
+            let _ = events.send(PipelineEvent::Chunk {
+                index: task.index,
+                source: task.source,
+                byte_range: task.byte_range,
+                usage,
+                text: summary.clone(),
+            });
+            report.reduced += 1;
+        } else {
+            let _ = events.send(PipelineEvent::SilentChunk {
+                index: task.index,
+                source: task.source,
+                byte_range: task.byte_range,
+                usage,
+            });
+            report.silent += 1;
         }
+    }
+    let _ = events.send(PipelineEvent::Completeness(report));
 
-        let trimmed_output = generated_text.trim();
-        if !trimmed_output.is_empty() && !trimmed_output.contains("特になし") {
-            // Rule of Silence: print only if output is notable
-            println!("[Chunk {}]\n{}", task.index, trimmed_output);
-            outputs.push((task.index, trimmed_output.to_string()));
+    if !per_source {
+        if !summary.is_empty() {
+            // Already counted in the usage of the chunk that produced it
+            let _ = events.send(PipelineEvent::FinalSummaryDelta { text: summary.clone() });
+            let _ = events.send(PipelineEvent::FinalSummary { usage: Usage::default(), text: summary });
         }
+        return Ok(());
+    }
+    if !summary.is_empty() {
+        send_source_summary(&events, current_source.clone(), &summary);
+        source_summaries.push((current_source, summary));
     }
-    
-    outputs
+    if config.combined_summary && !source_summaries.is_empty() {
+        let text: String = source_summaries
+            .iter()
+            .map(|(source, summary)| {
+                let label = source.as_deref().map_or("<stdin>".into(), Path::to_string_lossy);
+                format!("[Source: {}]\n{}\n\n", label, summary.trim())
+            })
+            .collect();
+        let prompt = fill(&config.cross_file_reduce_prompt, &[("{SYS_PROMPT}", &system_prompt), ("{TEXT}", &text)]);
+        check_fits(backend.as_ref(), &config, "cross-file reduce", &prompt)?;
+        let started = Instant::now();
+        let final_summary = session.generate(&prompt, &params, &mut |piece| {
+            let _ = events.send(PipelineEvent::FinalSummaryDelta { text: piece.to_string() });
+        })?;
+        let usage = Usage {
+            input_tokens: backend.count_tokens(&text)?,
+            output_tokens: backend.count_tokens(&final_summary)?,
+            elapsed: started.elapsed(),
+        };
+        let _ = events.send(PipelineEvent::FinalSummary { usage, text: final_summary });
+    }
+    Ok(())
+}
+
+/// Fills the placeholders of `template` in one pass, so a value that
+/// contains a placeholder (a summary quoting `{TEXT}`, say) is kept as it is.
+fn fill(template: &str, values: &[(&str, &str)]) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match values.iter().find(|(name, _)| rest.starts_with(name)) {
+            Some((name, value)) => {
+                output.push_str(value);
+                rest = &rest[name.len()..];
+            }
+            None => {
+                output.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+/// The chunk text for the worker prompt, with carried-over context marked so
+/// the model does not summarize it twice.
+fn task_text(config: &AppConfig, task: &ChunkTask) -> String {
+    if task.overlap > 0 {
+        config.overlap_text_template
+            .replace("{OVERLAP}", task.overlap_text())
+            .replace("{TEXT}", task.body())
+    } else {
+        task.text.clone()
+    }
+}
+
+/// Sends the refined summary of one source. It was already counted in the
+/// usage of the chunk that produced it.
+fn send_source_summary(events: &Sender<PipelineEvent>, source: Option<Arc<Path>>, summary: &str) {
+    let _ = events.send(PipelineEvent::SourceSummaryDelta { source: source.clone(), text: summary.to_string() });
+    let _ = events.send(PipelineEvent::SourceSummary { source, usage: Usage::default(), text: summary.to_string() });
 }
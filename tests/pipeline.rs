@@ -0,0 +1,484 @@
+//! Runs the whole map/reduce pipeline over `MockBackend`, without a model.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use lfm_cmd::config::ReduceStrategy;
+use lfm_cmd::merge::MergeConfig;
+use lfm_cmd::types::{ChunkResult, ChunkStatus};
+use lfm_cmd::{AppConfig, Error, MockBackend, Pipeline, PipelineEvent, PipelineOutput};
+
+/// Small enough that every line of the inputs below becomes its own chunk.
+const CHUNK_TOKENS: usize = 12;
+
+/// Templates that make the stage of a prompt easy to tell apart.
+fn tagged_config() -> AppConfig {
+    AppConfig {
+        meta_prompt_template: "META {TEXT}".to_string(),
+        worker_prompt_template: "CHUNK {TEXT}".to_string(),
+        intermediate_reduce_prompt: "INTERMEDIATE {TEXT}".to_string(),
+        final_reduce_prompt: "FINAL {TEXT}".to_string(),
+        cross_file_reduce_prompt: "CROSS {TEXT}".to_string(),
+        ..AppConfig::default()
+    }
+}
+
+/// Answers chunks with their text, meta prompts with a fixed instruction and
+/// summaries with the stage name and what they were given.
+fn tagged_backend() -> MockBackend {
+    MockBackend::with_responder(|prompt| match prompt.split_once(' ') {
+        Some(("CHUNK", text)) => text.trim().to_string(),
+        Some(("META", _)) => "要約してください".to_string(),
+        Some((stage, text)) => format!("{}<{}>", stage, text.trim()),
+        None => String::new(),
+    })
+}
+
+fn pipeline(backend: MockBackend, config: AppConfig, workers: usize) -> Pipeline {
+    Pipeline::builder(Arc::new(backend)).config(config).chunk_tokens(CHUNK_TOKENS).workers(workers).build()
+}
+
+fn indices(output: &PipelineOutput) -> Vec<usize> {
+    output.chunks.iter().map(|(index, _)| *index).collect()
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("lfm-cmd-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn scripted_run_summarizes_the_chunks() {
+    // One worker and two chunks keep the call order fixed: the meta prompt
+    // only starts once both chunk results are in
+    let backend = MockBackend::scripted(["猫の話", "名前の話", "指示", "全体の要約"]);
+    let output = pipeline(backend, AppConfig::default(), 1).run("吾輩は猫である。\n名前はまだ無い。\n".as_bytes()).unwrap();
+
+    assert_eq!(output.chunks, vec![(0, "猫の話".to_string()), (1, "名前の話".to_string())]);
+    assert_eq!(output.meta_prompt.as_deref(), Some("指示"));
+    assert_eq!(output.final_summary.as_deref(), Some("全体の要約"));
+    assert_eq!(output.stats.chunks, 2);
+    assert!(output.completeness.unwrap().is_complete());
+}
+
+#[test]
+fn silent_chunks_are_left_out_of_the_reduce() {
+    let backend = MockBackend::scripted(["猫の話", "特になし", "生れの話", "指示", "全体の要約"]);
+    let text = "吾輩は猫である。\n名前はまだ無い。\nどこで生れたか。\n";
+    let output = pipeline(backend, AppConfig::default(), 1).run(text.as_bytes()).unwrap();
+
+    assert_eq!(indices(&output), vec![0, 2]);
+    assert_eq!(output.silent_chunks, vec![1]);
+    assert_eq!(output.final_summary.as_deref(), Some("全体の要約"));
+    let report = output.completeness.unwrap();
+    assert_eq!((report.reduced, report.silent), (2, 1));
+    assert!(report.is_complete());
+}
+
+#[test]
+fn a_failed_meta_prompt_is_reported() {
+    let backend = MockBackend::with_responder(|prompt| match prompt.split_once(' ') {
+        Some(("META", _)) => panic!("generation failed"),
+        Some(("CHUNK", text)) => text.trim().to_string(),
+        Some((stage, text)) => format!("{}<{}>", stage, text.trim()),
+        None => String::new(),
+    });
+    let text = "吾輩は猫である。\n名前はまだ無い。\nどこで生れたか。\n";
+    let mut failures = Vec::new();
+    let result = pipeline(backend, tagged_config(), 2).stream(text.as_bytes(), |event| match event {
+        PipelineEvent::MetaPromptFailed { error } => failures.push(error),
+        PipelineEvent::MetaPrompt { text } => panic!("unexpected meta prompt {:?}", text),
+        _ => {}
+    });
+
+    // The reduce still runs, on the system prompt
+    result.unwrap();
+    assert_eq!(failures, vec!["meta prompt thread panicked".to_string()]);
+}
+
+#[test]
+fn chunks_arrive_in_input_order_with_several_workers() {
+    let lines: Vec<String> = (0..40).map(|i| format!("第{:02}番目の文です。", i)).collect();
+    let text = lines.join("\n");
+    let output = pipeline(tagged_backend(), tagged_config(), 4).run(text.as_bytes()).unwrap();
+
+    assert_eq!(indices(&output), (0..40).collect::<Vec<_>>());
+    for ((_, result), line) in output.chunks.iter().zip(&lines) {
+        assert_eq!(result, line);
+    }
+    // Every chunk result reaches the final summary, in order
+    let summary = output.final_summary.unwrap();
+    assert!(summary.starts_with("FINAL<[Data 0]\n第00番目の文です。"));
+    assert!(summary.contains("[Data 39]\n第39番目の文です。"));
+}
+
+#[test]
+fn tree_reduce_keeps_every_chunk_in_order() {
+    let config = AppConfig { reduce_strategy: ReduceStrategy::Tree, reduce_fan_in: 3, ..tagged_config() };
+    let lines: Vec<String> = (0..40).map(|i| format!("第{:02}番目の文です。", i)).collect();
+    let output = pipeline(tagged_backend(), config, 4).run(lines.join("\n").as_bytes()).unwrap();
+
+    // 40 results, then 14, 5 and 2 intermediate summaries
+    assert_eq!(output.intermediate_summaries.len(), 14 + 5 + 2);
+    let summary = output.final_summary.unwrap();
+    assert!(summary.starts_with("FINAL<[Intermediate Summary 19]\nINTERMEDIATE<"));
+    let positions: Vec<usize> = lines.iter().map(|line| summary.find(line.as_str()).unwrap()).collect();
+    assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+#[test]
+fn tree_reduce_levels_stay_under_the_threshold() {
+    let config = AppConfig {
+        reduce_strategy: ReduceStrategy::Tree,
+        reduce_fan_in: 8,
+        reduce_threshold_tokens: Some(70),
+        ..tagged_config()
+    };
+    let backend = MockBackend::with_responder(|prompt| match prompt.split_once(' ') {
+        Some(("CHUNK", text)) => text.trim().to_string(),
+        Some(("META", _)) => "要約してください".to_string(),
+        Some(("INTERMEDIATE", _)) => "要約です".to_string(),
+        Some((_, text)) => text.to_string(),
+        None => String::new(),
+    });
+    let lines: Vec<String> = (0..12).map(|i| format!("第{:02}番目の文です。", i)).collect();
+    let output = pipeline(backend, config, 2).run(lines.join("\n").as_bytes()).unwrap();
+
+    // Groups of three results, then the four summaries (fewer than
+    // `reduce_fan_in`, but over the threshold together) in pairs
+    assert_eq!(output.intermediate_summaries.len(), 4 + 2);
+    let summary = output.final_summary.unwrap();
+    assert!(summary.chars().count() <= 70, "{}", summary);
+}
+
+/// The refine strategy with templates that show which step wrote what.
+fn refine_config() -> AppConfig {
+    AppConfig {
+        reduce_strategy: ReduceStrategy::Refine,
+        refine_prompt_template: "REFINE {PREV_SUMMARY}|{TEXT}".to_string(),
+        ..tagged_config()
+    }
+}
+
+/// Starts the summary with the first chunk and appends every later one.
+fn refine_step(prompt: &str) -> String {
+    match prompt.split_once(' ') {
+        Some(("CHUNK", text)) => text.trim().to_string(),
+        Some(("REFINE", rest)) => {
+            let (summary, text) = rest.rsplit_once('|').unwrap();
+            format!("{}+{}", summary, text.trim())
+        }
+        Some((stage, text)) => format!("{}<{}>", stage, text.trim()),
+        None => String::new(),
+    }
+}
+
+fn refine_backend() -> MockBackend {
+    MockBackend::with_responder(refine_step)
+}
+
+#[test]
+fn refine_carries_the_summary_forward() {
+    let text = "吾輩は猫である。\n名前はまだ無い。\nどこで生れたか。\n";
+    let output = pipeline(refine_backend(), refine_config(), 2).run(text.as_bytes()).unwrap();
+
+    let steps: Vec<&str> = output.chunks.iter().map(|(_, step)| step.as_str()).collect();
+    assert_eq!(steps, ["吾輩は猫である。", "吾輩は猫である。+名前はまだ無い。", "吾輩は猫である。+名前はまだ無い。+どこで生れたか。"]);
+    assert_eq!(output.final_summary.as_deref(), Some(steps[2]));
+    assert!(output.completeness.unwrap().is_complete());
+}
+
+#[test]
+fn refine_keeps_the_summary_over_silent_and_invalid_steps() {
+    let text = "吾輩は猫である。\n名前はまだ無い。\nどこで生れたか。\n";
+    let backend = MockBackend::with_responder(|prompt| match prompt.split_once(' ') {
+        Some((_, text)) if text.trim().ends_with("名前はまだ無い。") => "特になし".to_string(),
+        _ => refine_step(prompt),
+    });
+    let output = pipeline(backend, refine_config(), 1).run(text.as_bytes()).unwrap();
+
+    assert_eq!(output.silent_chunks, vec![1]);
+    assert_eq!(output.final_summary.as_deref(), Some("吾輩は猫である。+どこで生れたか。"));
+
+    // Steps append the chunk to a JSON list, and one breaks the schema
+    let config = AppConfig { schema: Some(serde_json::json!({ "type": "array" })), ..refine_config() };
+    let backend = MockBackend::with_responder(|prompt| {
+        let (summary, text) = match prompt.split_once(' ') {
+            Some(("CHUNK", text)) => ("[]", text.trim()),
+            Some(("REFINE", rest)) => rest.rsplit_once('|').map(|(summary, text)| (summary, text.trim())).unwrap(),
+            _ => return String::new(),
+        };
+        if text.contains("名前") {
+            return "{\"broken\": true}".to_string();
+        }
+        let mut list: Vec<String> = serde_json::from_str(summary).unwrap();
+        list.push(text.to_string());
+        serde_json::to_string(&list).unwrap()
+    });
+    let output = pipeline(backend, config, 1).run(text.as_bytes()).unwrap();
+
+    assert_eq!(output.invalid_chunks.len(), 1);
+    assert_eq!(output.final_summary.as_deref(), Some("[\"吾輩は猫である。\",\"どこで生れたか。\"]"));
+    let report = output.completeness.unwrap();
+    assert_eq!((report.reduced, report.failed), (2, vec![1]));
+}
+
+#[test]
+fn refine_starts_over_for_every_source() {
+    let dir = temp_dir("refine");
+    let a = dir.join("a.txt");
+    let b = dir.join("b.txt");
+    std::fs::write(&a, "吾輩は猫である。\n名前はまだ無い。\n").unwrap();
+    std::fs::write(&b, "どこで生れたか。\nとんと見当がつかぬ。\n").unwrap();
+    let config = AppConfig { combined_summary: true, ..refine_config() };
+    let output = pipeline(refine_backend(), config, 1).run_files(&[a, b]).unwrap();
+
+    let summaries: Vec<&str> = output.source_summaries.iter().map(|(_, summary)| summary.as_str()).collect();
+    assert_eq!(summaries, ["吾輩は猫である。+名前はまだ無い。", "どこで生れたか。+とんと見当がつかぬ。"]);
+    let summary = output.final_summary.unwrap();
+    assert!(summary.starts_with("CROSS<[Source: "));
+    assert!(summary.ends_with("どこで生れたか。+とんと見当がつかぬ。>"));
+}
+
+#[test]
+fn refine_keeps_placeholders_inside_the_summary() {
+    let backend = MockBackend::with_responder(|prompt| match prompt.split_once(' ') {
+        Some(("CHUNK", _)) => "{TEXT} と {SYS_PROMPT} を含む要約".to_string(),
+        Some(("REFINE", rest)) => rest.to_string(),
+        _ => String::new(),
+    });
+    let output = pipeline(backend, refine_config(), 1).run("吾輩は猫である。\n名前はまだ無い。\n".as_bytes()).unwrap();
+
+    assert_eq!(output.chunks[1].1, "{TEXT} と {SYS_PROMPT} を含む要約|名前はまだ無い。");
+}
+
+#[test]
+fn refine_fails_a_step_that_does_not_fit() {
+    // The summary grows with every step (up to the 20 tokens a step may
+    // write) until the prompt leaves too little room: the third step needs 38
+    let config = AppConfig { main_ctx_size: 50, reduce_output_tokens: 20, ..refine_config() };
+    let lines: Vec<String> = (0..8).map(|i| format!("第{:02}番目の文です。", i)).collect();
+    let mut completeness = None;
+    let result = pipeline(refine_backend(), config, 1).stream(lines.join("\n").as_bytes(), |event| {
+        if let PipelineEvent::Completeness(report) = event {
+            completeness = Some(report);
+        }
+    });
+
+    assert!(matches!(&result, Err(Error::Inference(message)) if message.contains("the refine prompt")), "{:?}", result.err());
+    let report = completeness.unwrap();
+    assert_eq!((report.reduced, report.failed), (2, vec![2]));
+}
+
+#[test]
+fn overlap_is_marked_in_the_worker_prompt() {
+    let config = AppConfig {
+        chunk_overlap_tokens: 9,
+        overlap_text_template: "(文脈: {OVERLAP}) {TEXT}".to_string(),
+        ..tagged_config()
+    };
+    let text = "吾輩は猫である。\n名前はまだ無い。\nどこで生れたか。\n";
+    let output = pipeline(tagged_backend(), config, 1).run(text.as_bytes()).unwrap();
+
+    let results: Vec<&str> = output.chunks.iter().map(|(_, result)| result.as_str()).collect();
+    assert_eq!(results, ["吾輩は猫である。", "(文脈: 吾輩は猫である。\n) 名前はまだ無い。", "(文脈: 名前はまだ無い。\n) どこで生れたか。"]);
+}
+
+#[test]
+fn invalid_chunks_are_reported_as_failed() {
+    let config = AppConfig {
+        schema: Some(serde_json::json!({ "type": "object", "required": ["topic"] })),
+        ..tagged_config()
+    };
+    let backend = MockBackend::with_responder(|prompt| match prompt.split_once(' ') {
+        Some(("CHUNK", text)) if text.contains("名前") => "{\"name\": null}".to_string(),
+        Some(("CHUNK", _)) => "{\"topic\": \"猫\"}".to_string(),
+        Some(("META", _)) => "要約してください".to_string(),
+        _ => "{\"topic\": \"全体\"}".to_string(),
+    });
+    let text = "吾輩は猫である。\n名前はまだ無い。\nどこで生れたか。\n";
+    let output = pipeline(backend, config, 2).run(text.as_bytes()).unwrap();
+
+    assert_eq!(indices(&output), vec![0, 2]);
+    assert_eq!(output.invalid_chunks.len(), 1);
+    assert_eq!(output.invalid_chunks[0].0, 1);
+    assert_eq!(output.completeness.unwrap().failed, vec![1]);
+    assert_eq!(output.final_summary.as_deref(), Some("{\"topic\": \"全体\"}"));
+}
+
+#[test]
+fn a_panicking_worker_fails_the_run() {
+    let backend = MockBackend::with_responder(|prompt| {
+        if prompt.contains("名前") {
+            panic!("generation failed");
+        }
+        prompt.to_string()
+    });
+    let text = "吾輩は猫である。\n名前はまだ無い。\nどこで生れたか。\n";
+    let result = pipeline(backend, tagged_config(), 2).run(text.as_bytes());
+
+    assert!(matches!(result, Err(Error::ThreadPanicked("worker"))), "{:?}", result.err());
+}
+
+#[test]
+fn chunks_lost_at_the_end_are_reported_missing() {
+    // The last two chunks never reach the reducer, so no later result shows the gap
+    let backend = MockBackend::with_responder(|prompt| match prompt.split_once(' ') {
+        Some(("CHUNK", text)) if text.contains("生れ") || text.contains("見当") => panic!("generation failed"),
+        Some(("CHUNK", text)) => text.trim().to_string(),
+        Some(("META", _)) => "要約してください".to_string(),
+        Some((stage, text)) => format!("{}<{}>", stage, text.trim()),
+        None => String::new(),
+    });
+    let text = "吾輩は猫である。\n名前はまだ無い。\nどこで生れたか。\nとんと見当がつかぬ。\n";
+    let mut completeness = None;
+    let result = pipeline(backend, tagged_config(), 2).stream(text.as_bytes(), |event| {
+        if let PipelineEvent::Completeness(report) = event {
+            completeness = Some(report);
+        }
+    });
+
+    assert!(matches!(result, Err(Error::ThreadPanicked("worker"))), "{:?}", result.err());
+    let report = completeness.unwrap();
+    assert_eq!(report.reduced, 2);
+    assert_eq!(report.missing, vec![2, 3]);
+}
+
+#[test]
+fn a_reduce_prompt_over_the_context_fails_the_run() {
+    // The threshold lets the buffer grow past what the context leaves for
+    // the prompt once `reduce_output_tokens` are kept free
+    let config = AppConfig {
+        main_ctx_size: 200,
+        reduce_output_tokens: 100,
+        reduce_threshold_tokens: Some(150),
+        ..tagged_config()
+    };
+    let lines: Vec<String> = (0..20).map(|i| format!("第{:02}番目の文です。", i)).collect();
+    let result = pipeline(tagged_backend(), config.clone(), 1).run(lines.join("\n").as_bytes());
+
+    match result {
+        Err(error @ Error::Inference(_)) => {
+            assert!(error.to_string().contains("the intermediate reduce prompt has "), "{}", error);
+            assert_eq!(error.exit_code(), 5);
+        }
+        other => panic!("{:?}", other.err()),
+    }
+    let config = AppConfig { reduce_strategy: ReduceStrategy::Tree, ..config };
+    let result = pipeline(tagged_backend(), config, 2).run(lines.join("\n").as_bytes());
+    assert!(matches!(&result, Err(Error::Inference(message)) if message.contains("the tree reduce prompt")), "{:?}", result.err());
+}
+
+#[test]
+fn an_oversized_result_is_shortened_instead_of_failing_the_run() {
+    // One chunk result alone is larger than the whole context leaves for a reduce prompt
+    let config = AppConfig { main_ctx_size: 400, reduce_output_tokens: 50, reduce_threshold_tokens: Some(150), ..tagged_config() };
+    let backend = || {
+        MockBackend::with_responder(|prompt| match prompt.split_once(' ') {
+            Some(("CHUNK", text)) if text.contains("名前") => text.trim().repeat(50),
+            Some(("CHUNK", text)) => text.trim().to_string(),
+            Some(("META", _)) => "要約してください".to_string(),
+            Some((stage, text)) => format!("{}<{}>", stage, text.trim()),
+            None => String::new(),
+        })
+    };
+    let text = "吾輩は猫である。\n名前はまだ無い。\nどこで生れたか。\n";
+
+    for strategy in [ReduceStrategy::Rolling, ReduceStrategy::Tree] {
+        let config = AppConfig { reduce_strategy: strategy, ..config.clone() };
+        let output = pipeline(backend(), config, 2).run(text.as_bytes()).unwrap();
+
+        // The long result is reduced alone before it joins the others
+        assert!(output.intermediate_summaries[0].starts_with("INTERMEDIATE<[Data 1]\n名前はまだ無い。"), "{:?}", strategy);
+        assert!(output.final_summary.unwrap().starts_with("FINAL<"), "{:?}", strategy);
+        assert_eq!(output.completeness.unwrap().reduced, 3);
+    }
+}
+
+#[test]
+fn reduce_generations_stop_at_reduce_output_tokens() {
+    let config = AppConfig { reduce_output_tokens: 30, reduce_threshold_tokens: Some(60), ..tagged_config() };
+    let long = "長".repeat(500);
+    let answer = long.clone();
+    let backend = MockBackend::with_responder(move |prompt| match prompt.split_once(' ') {
+        Some(("CHUNK", text)) => text.trim().to_string(),
+        Some(("META", _)) => "要約してください".to_string(),
+        _ => answer.clone(),
+    });
+    let lines: Vec<String> = (0..12).map(|i| format!("第{:02}番目の文です。", i)).collect();
+    let output = pipeline(backend, config.clone(), 1).run(lines.join("\n").as_bytes()).unwrap();
+
+    assert!(!output.intermediate_summaries.is_empty());
+    for summary in &output.intermediate_summaries {
+        assert_eq!(summary.chars().count(), 30);
+    }
+    assert_eq!(output.final_summary.unwrap().chars().count(), 30);
+
+    // The merge overview is capped the same way
+    let config = AppConfig { merge: MergeConfig { enabled: true, keys: Vec::new(), overview: true }, ..config };
+    let backend = MockBackend::with_responder(move |prompt| match prompt.split_once(' ') {
+        Some(("CHUNK", _)) => "{\"topic\": \"猫\"}".to_string(),
+        _ => long.clone(),
+    });
+    let output = pipeline(backend, config, 1).run(lines.join("\n").as_bytes()).unwrap();
+    assert_eq!(output.final_summary.unwrap().chars().count(), 30);
+}
+
+#[test]
+fn a_missing_input_file_fails_the_run() {
+    let missing = temp_dir("missing").join("no-such-file.txt");
+    let result = pipeline(tagged_backend(), tagged_config(), 2).run_files(&[missing]);
+
+    assert!(matches!(result, Err(Error::Io(_))), "{:?}", result.err());
+}
+
+#[test]
+fn run_files_summarizes_every_file() {
+    let dir = temp_dir("files");
+    let a = dir.join("a.txt");
+    let b = dir.join("b.txt");
+    std::fs::write(&a, "吾輩は猫である。\n名前はまだ無い。\n").unwrap();
+    std::fs::write(&b, "どこで生れたか。\nとんと見当がつかぬ。\n").unwrap();
+    let config = AppConfig { combined_summary: true, ..tagged_config() };
+    let output = pipeline(tagged_backend(), config, 2).run_files(&[a.clone(), b.clone()]).unwrap();
+
+    // Chunk numbering continues across files
+    assert_eq!(indices(&output), vec![0, 1, 2, 3]);
+    let sources: Vec<_> = output.source_summaries.iter().map(|(source, _)| source.as_deref().unwrap().to_path_buf()).collect();
+    assert_eq!(sources, vec![a, b]);
+    assert!(output.source_summaries[0].1.contains("名前はまだ無い。"));
+    assert!(!output.source_summaries[0].1.contains("見当"));
+    let summary = output.final_summary.unwrap();
+    assert!(summary.starts_with("CROSS<[Source: "));
+    assert_eq!(summary.matches("[Source: ").count(), 2);
+}
+
+#[test]
+fn run_results_reduces_saved_results() {
+    let result = |index, text: &str| ChunkResult { index, source: None, status: ChunkStatus::Output(text.to_string()) };
+    let results = vec![result(0, "猫の話"), result(1, "名前の話")];
+    let output = pipeline(tagged_backend(), tagged_config(), 2).run_results(results).unwrap();
+
+    // No chunk is generated again: the saved outputs go straight to the reducer
+    assert!(output.chunks.is_empty());
+    assert_eq!(output.final_summary.as_deref(), Some("FINAL<[Data 0]\n猫の話\n\n[Data 1]\n名前の話>"));
+}
+
+#[test]
+fn saved_results_account_for_silent_failed_and_missing_chunks() {
+    let result = |index, status| ChunkResult { index, source: None, status };
+    let results = vec![
+        result(0, ChunkStatus::Output("猫の話".to_string())),
+        result(1, ChunkStatus::Silent),
+        result(2, ChunkStatus::Failed),
+        result(4, ChunkStatus::Output("見当の話".to_string())),
+    ];
+    let output = pipeline(tagged_backend(), tagged_config(), 2).run_results(results).unwrap();
+
+    assert_eq!(output.final_summary.as_deref(), Some("FINAL<[Data 0]\n猫の話\n\n[Data 4]\n見当の話>"));
+    let report = output.completeness.unwrap();
+    assert_eq!((report.reduced, report.silent), (2, 1));
+    assert_eq!(report.failed, vec![2]);
+    assert_eq!(report.missing, vec![3]);
+}